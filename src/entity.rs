@@ -1,35 +1,50 @@
 // data entity
 
 use crate::property::{self, Value2, KT};
+use crate::storage;
 use crate::transaction;
-use crate::transaction::EntityChanges;
+use crate::transaction::{EntityChanges, Savepoint, Storable, TransactionInfo, TypeRegistry};
+use crate::Error;
 //use core::borrow;
-use std::any::{Any, TypeId};
-use std::borrow::Borrow;
-use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt;
-use std::io;
-use std::io::{Error, ErrorKind, Read, Write};
+use std::io::{Read, Write};
 use std::mem;
-use std::rc::Rc;
-
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::rc::{Rc, Weak};
+use std::time::SystemTime;
 
 /// Name of a document entity.
 /// Documents may be nested one another; therefore, the name is a vector.
 /// The name of the removed entity is reserved and will never be used again.
 pub type Name = Vec<u32>; // use SmallVec smallvec::*; or possible store as one number
 
-const CHG_CREATED: u32 = 1;
-const CHG_DEL_PROP: u32 = 2;
-const CHG_UPD_PROP: u32 = 4;
-const CHG_ADD_PROP: u32 = 8;
-const CHG_DELETED: u32 = 16;
+/// Identifier of a committed transaction in the history tree, never reused within the document
+pub type NodeId = u64;
+
+/// The entity has been created
+pub const CHG_CREATED: u32 = 1;
+/// A property has been removed from the entity
+pub const CHG_DEL_PROP: u32 = 2;
+/// A property of the entity has got a new value
+pub const CHG_UPD_PROP: u32 = 4;
+/// A property has been added to the entity
+pub const CHG_ADD_PROP: u32 = 8;
+/// The entity has been deleted
+pub const CHG_DELETED: u32 = 16;
+
+// kinds of the journal records
+const JOURNAL_COMMIT: u8 = 1;
+const JOURNAL_UNDO: u8 = 2;
+const JOURNAL_JUMP: u8 = 3;
+const JOURNAL_PRUNE: u8 = 4;
 
 /// Minimal (and initial) entity name
 pub const START_NAME: u32 = 0;
 
+#[derive(Clone)]
 pub struct Entity {
     /// Full entity name in the document
     pub name: Name,
@@ -42,69 +57,74 @@ pub struct Entity {
 }
 
 impl fmt::Debug for property::Value2 {
-    // Required method
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        //let ref b = self.value;
-
-        //let val: dyn Any + 'static = b.borrow();
-
-        write!(f, "  {}: ", self.key)?;
-
-        //        let c = an.downcast_ref::<&dyn std::fmt::Display>();
-
-        let a: &dyn Any = self.value.borrow();
-        let c = a.downcast_ref::<&dyn std::fmt::Display>();
-
-        //        writeln!(f, "{}", c.is_some())?;
-
-        let aa: &dyn Any = &(1, 2) as &dyn Any;
-
-        //let b:  = a.try_into();
-
-        //        if let Some(f) = self.value.downcast_ref::<dyn std::fmt::Display>() {
-        //        }
-
-        //let an: &dyn std::any::Any = self.value.borrow();
-        //writeln!(f, "{:?}", an)?;
-
-        //        (&an as &dyn std::fmt::Debug).fmt(f);
+        write!(f, "{}: ", self.key)?;
+        fmt_builtin(self.value.as_ref(), f)
+    }
+}
 
-        //let v: &std::fmt::Debug = an.into();
+/// Format a value of one of the standard types, the values of other types are shown as `Any`
+pub(crate) fn fmt_builtin(value: &dyn Any, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    macro_rules! fmt_as {
+        ($($t:ty),*) => {
+            $(
+                if let Some(v) = value.downcast_ref::<$t>() {
+                    return fmt::Debug::fmt(v, f);
+                }
+            )*
+        };
+    }
+    fmt_as!(i8, i16, i32, i64, u8, u16, u32, u64, f32, f64, bool, String, &str);
+    fmt::Debug::fmt(value, f)
+}
 
-        //let v = an.downcast_ref::<std::fmt::Debug>();
+/// Write the entity with its children as an indented tree,
+/// the properties are formatted by the registry if specified
+fn fmt_tree(
+    f: &mut fmt::Formatter<'_>,
+    entity: &Entity,
+    types: Option<&TypeRegistry>,
+    depth: usize,
+) -> fmt::Result {
+    let name: Vec<String> = entity.name.iter().map(|n| n.to_string()).collect();
+    write!(
+        f,
+        "{:indent$}#{} {{",
+        "",
+        name.join(","),
+        indent = depth * 2
+    )?;
+    for (i, prop) in entity.props2.iter().enumerate() {
+        f.write_str(if i == 0 { " " } else { ", " })?;
+        match types {
+            Some(types) => types.fmt_value(prop, f)?,
+            None => fmt::Debug::fmt(prop, f)?,
+        }
+    }
+    writeln!(f, "{}}}", if entity.props2.is_empty() { "" } else { " " })?;
 
-        //let v = self.value.downcast_ref::<&dyn std::fmt::Display>();
-        //writeln!(f, "{}", v.is_some())?;
+    for child in entity.children.iter().flatten() {
+        fmt_tree(f, child, types, depth + 1)?;
+    }
+    Ok(())
+}
 
-        if self.value.is::<i32>() {
-            writeln!(f, "{}", self.value.downcast_ref::<i32>().unwrap())?;
-        } else if self.value.is::<&str>() {
-            writeln!(f, "\"{}\"", self.value.downcast_ref::<&str>().unwrap())?;
-        } else {
-            self.value.fmt(f)?;
-            //writeln!(f, " unsupported type!")?;
-        }
+/// Entity formatted by the type registry, see TypeRegistry::debug
+pub struct EntityDebug<'a> {
+    pub(crate) types: &'a TypeRegistry,
+    pub(crate) entity: &'a Entity,
+}
 
-        Ok(())
+impl fmt::Debug for EntityDebug<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_tree(f, self.entity, Some(self.types), 0)
     }
 }
 
 impl fmt::Debug for Entity {
-    // Required method
+    /// The keys are shown as numbers, use TypeRegistry::debug to show them by names
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(
-            f,
-            "Entity #{}:",
-            self.name
-                .iter()
-                .map(|n| format!("{n}"))
-                .collect::<Vec<String>>()
-                .join(",")
-        )?;
-        for p in &self.props2 {
-            writeln!(f, "  {}", p.key)?;
-        }
-        Ok(())
+        fmt_tree(f, self, None, 0)
     }
 }
 
@@ -112,18 +132,14 @@ impl Entity {
     pub fn get_property<T: Copy + 'static>(&self, key: property::KT) -> Option<T> {
         if let Some(pos) = self.props2.iter().position(|p| p.key == key) {
             if let Some(v) = self.props2[pos].value.downcast_ref::<T>() {
-                return Some(v.clone());
+                return Some(*v);
             }
         }
         None
     }
 
     pub fn get_property_ptr(&self, key: property::KT) -> Option<Rc<Value2>> {
-        if let Some(pos) = self.props2.iter().position(|p| p.key == key) {
-            Some(self.props2[pos].clone())
-        } else {
-            None
-        }
+        self.props2.iter().find(|p| p.key == key).cloned()
     }
 
     pub fn properties(&self) -> &Vec<Rc<Value2>> {
@@ -148,37 +164,51 @@ impl Entity {
         }
     }
 
+    /// Change a property of this entity, which has the full name specified
     fn apply_changes(
         &mut self,
+        types: &TypeRegistry,
+        name: &Name,
         changes: &transaction::PropChange,
-        storages: &mut Vec<TransactionStorage>,
-    ) -> Result<ChangedEntities, &'static str> {
+        storages: &mut dyn InsertedStorages,
+        reverts: &mut Vec<Revert>,
+    ) -> Result<ChangedEntities, Error> {
         match changes {
             transaction::PropChange::Update(prop_ptr) => {
                 //let prop_discr = mem::discriminant(prop_ptr.as_ref());
                 if let Some(pos) = self.props2.iter().position(|p| p.key == prop_ptr.key) {
-                    self.props2[pos] = prop_ptr.clone();
-                    Ok(ChangedEntities::from(&self.name, CHG_UPD_PROP))
+                    if types.eq(&self.props2[pos], prop_ptr) {
+                        // assigning the same value changes nothing
+                        return Ok(ChangedEntities::new());
+                    }
+                    let value = mem::replace(&mut self.props2[pos], prop_ptr.clone());
+                    reverts.push(Revert::SetProp {
+                        name: name.clone(),
+                        pos,
+                        value,
+                    });
+                    Ok(ChangedEntities::from(name, CHG_UPD_PROP, prop_ptr.key))
                 } else {
                     self.props2.push(prop_ptr.clone());
-                    let mut entity_changes = ChangedEntities::from(&self.name, CHG_ADD_PROP);
+                    reverts.push(Revert::RemoveProp {
+                        name: name.clone(),
+                        pos: self.props2.len() - 1,
+                    });
+                    let mut entity_changes =
+                        ChangedEntities::from(name, CHG_ADD_PROP, prop_ptr.key);
 
                     if prop_ptr.key == property::INS_DOC {
                         if let Some(doc_id) = prop_ptr.value.downcast_ref::<property::DocId>() {
-                            // open inserted document and apply transaction from it to the children of this entity
-                            let storage = Document::get_or_open_transactions(storages, *doc_id);
-                            let mut content: Vec<Entity> = vec![];
-                            let united_trs = transaction::Transaction::merge(&storage.htrs);
-                            let changes = Document::apply_transaction_private(
-                                &united_trs,
-                                &mut content,
-                                storages,
-                            )?;
-                            entity_changes.merge(changes);
-                            self.children = Some(content);
+                            let (content, changes) =
+                                Document::inserted_content(types, storages, *doc_id)?;
+                            entity_changes.merge(changes.prefixed(name));
+                            reverts.push(Revert::SetChildren {
+                                name: name.clone(),
+                                children: self.children.replace(content),
+                            });
                             //}
                         } else {
-                            return Err("unexpected document id type");
+                            return Err(Error::TypeMismatch(property::INS_DOC));
                         }
                     }
                     Ok(entity_changes)
@@ -188,9 +218,14 @@ impl Entity {
             transaction::PropChange::Delete(key) => {
                 // removal of a property from an entity
                 if let Some(pos) = self.props2.iter().position(|p| p.key == *key) {
-                    self.props2.swap_remove(pos);
+                    let value = self.props2.swap_remove(pos);
+                    reverts.push(Revert::InsertProp {
+                        name: name.clone(),
+                        pos,
+                        value,
+                    });
                 } // all attempts to delete a non-existent property are ignored
-                Ok(ChangedEntities::from(&self.name, CHG_DEL_PROP))
+                Ok(ChangedEntities::from(name, CHG_DEL_PROP, *key))
             }
         }
     }
@@ -198,235 +233,1804 @@ impl Entity {
     fn insert_document() {
         // TODO
     }
+
+    fn save(&self, types: &TypeRegistry, w: &mut dyn Write) -> Result<(), Error> {
+        transaction::write_name(&self.name, w)?;
+        transaction::write_len(self.props2.len(), w)?;
+        for prop in &self.props2 {
+            types.write_value(prop, w)?;
+        }
+        match &self.children {
+            None => Ok(w.write_all(&[0])?),
+            Some(chlds) => {
+                w.write_all(&[1])?;
+                Entity::save_all(chlds, types, w)
+            }
+        }
+    }
+
+    fn load(types: &TypeRegistry, r: &mut dyn Read) -> Result<Self, Error> {
+        let name = transaction::read_name(r)?;
+        let count = transaction::read_len(r)?;
+        let mut props2 = Vec::with_capacity(count.min(64));
+        for _ in 0..count {
+            props2.push(Rc::new(types.read_value(r)?));
+        }
+        let mut has_children = [0u8; 1];
+        r.read_exact(&mut has_children)?;
+        let children = match has_children[0] {
+            0 => None,
+            1 => Some(Entity::load_all(types, r)?),
+            _ => return Err(Error::Corrupted("unknown kind of entity children")),
+        };
+        Ok(Entity {
+            name,
+            props2,
+            children,
+        })
+    }
+
+    fn save_all(entities: &[Entity], types: &TypeRegistry, w: &mut dyn Write) -> Result<(), Error> {
+        transaction::write_len(entities.len(), w)?;
+        for entity in entities {
+            entity.save(types, w)?;
+        }
+        Ok(())
+    }
+
+    fn load_all(types: &TypeRegistry, r: &mut dyn Read) -> Result<Vec<Entity>, Error> {
+        let count = transaction::read_len(r)?;
+        let mut entities = Vec::with_capacity(count.min(1024));
+        for _ in 0..count {
+            entities.push(Entity::load(types, r)?);
+        }
+        Ok(entities)
+    }
 }
 
 #[derive(Clone)]
 pub struct PlainEntity {
+    /// Full name of the copied entity, the links to it are redirected to its pasted copy
+    pub name: Name,
     pub props: Vec<Rc<property::Value2>>,
+    /// Children of the inserted document with the local overrides made to them
+    pub children: Option<Vec<PlainEntity>>,
 }
 
 impl PlainEntity {
-    fn new(e: &Entity) -> Self {
+    fn new(name: Name, e: &Entity) -> Self {
+        let children = e.children.as_ref().map(|chlds| {
+            chlds
+                .iter()
+                .map(|child| {
+                    let mut child_name = name.clone();
+                    child_name.extend(child.name.last());
+                    PlainEntity::new(child_name, child)
+                })
+                .collect()
+        });
         PlainEntity {
+            name,
             props: e.props2.clone(),
+            children,
         }
     }
-}
 
-// The objects of an application that uses this library should implement this trait.
-//pub trait EntityUser {
-//    fn on_change(&mut self, entity: &Entity, flags: u32) -> bool;
-//    // ...
-//}
-//
-//pub fn entity_user_factory(_entity: &Entity) -> Option<Rc<dyn EntityUser>> {
-//    // detect required object type by the properties and create it
-//    unimplemented!();
-//}
+    /// Call the function for the entity and all its children, the parents before the children
+    fn visit<'a>(&'a self, f: &mut dyn FnMut(&'a PlainEntity)) {
+        f(self);
+        for child in self.children.iter().flatten() {
+            child.visit(f);
+        }
+    }
 
-pub struct ChangedEntities {
-    // TODO use transaction::Changes instead
-    pub data: HashMap<Name, u32>,
-}
+    fn visit_mut(&mut self, f: &mut dyn FnMut(&mut PlainEntity)) {
+        f(self);
+        for child in self.children.iter_mut().flatten() {
+            child.visit_mut(f);
+        }
+    }
 
-impl ChangedEntities {
-    fn new() -> Self {
-        ChangedEntities {
-            data: HashMap::new(),
+    /// The name isn't empty, the name of every child extends the name of its parent by one identifier
+    fn check_names(&self) -> Result<(), Error> {
+        if self.name.is_empty() {
+            return Err(Error::Corrupted("empty name of copied entity"));
         }
+        for child in self.children.iter().flatten() {
+            if child.name.len() != self.name.len() + 1 || !child.name.starts_with(&self.name) {
+                return Err(Error::Corrupted("wrong name of copied child"));
+            }
+            child.check_names()?;
+        }
+        Ok(())
     }
 
-    fn from(name: &Name, flags: u32) -> Self {
-        ChangedEntities {
-            data: HashMap::from([(name.clone(), flags)]),
+    /// The entity as it should be among the children of the pasted inserted document
+    fn to_entity(&self) -> Entity {
+        Entity {
+            name: self.name.last().into_iter().copied().collect(),
+            props2: self.props.clone(),
+            children: self
+                .children
+                .as_ref()
+                .map(|chlds| chlds.iter().map(PlainEntity::to_entity).collect()),
         }
     }
 
-    fn add(&mut self, name: &Name, flags: u32) {
-        if let Some(old_flags) = self.data.insert(name.clone(), flags) {
-            self.data.insert(name.clone(), flags | old_flags);
+    /// Write the copied entities, e.g. to pass them to another process through the system clipboard.
+    /// The names of the used keys are written too, to check that they have the same meaning
+    /// where the entities are read.
+    pub fn save_all(
+        clipboard: &[PlainEntity],
+        types: &TypeRegistry,
+        w: &mut dyn Write,
+    ) -> Result<(), Error> {
+        storage::write_clipboard_header(w)?;
+
+        let mut keys = BTreeSet::new();
+        for entity in clipboard {
+            entity.visit(&mut |e| keys.extend(e.props.iter().map(|p| p.key)));
+        }
+        let mut payload = vec![];
+        transaction::write_len(keys.len(), &mut payload)?;
+        for key in keys {
+            payload.write_all(&key.to_le_bytes())?;
+            types
+                .key_name(key)
+                .unwrap_or_default()
+                .to_owned()
+                .store(&mut payload)?;
         }
+
+        // the entities are written as the content is, the children with their own identifiers
+        let entities: Vec<Entity> = clipboard
+            .iter()
+            .map(|e| Entity {
+                name: e.name.clone(),
+                ..e.to_entity()
+            })
+            .collect();
+        Entity::save_all(&entities, types, &mut payload)?;
+        Ok(storage::write_record(&payload, w)?)
     }
 
-    fn merge(&mut self, other: Self) {
-        for (name, flags) in other.data {
-            self.add(&name, flags);
+    /// Read the entities written with PlainEntity::save_all, they may be pasted with Document::paste
+    pub fn load_all(types: &TypeRegistry, r: &mut dyn Read) -> Result<Vec<PlainEntity>, Error> {
+        storage::read_clipboard_header(r)?;
+
+        let payload = storage::read_record(r)?;
+        let mut payload = payload.as_slice();
+        for _ in 0..transaction::read_len(&mut payload)? {
+            let mut key = [0u8; 4];
+            payload.read_exact(&mut key)?;
+            let name = String::load(&mut payload)?;
+            if !name.is_empty() {
+                check_key_name(types, KT::from_le_bytes(key), &name)?;
+            }
         }
+
+        let mut clipboard = vec![];
+        for entity in Entity::load_all(types, &mut payload)? {
+            if !has_local_names(&entity) {
+                return Err(Error::Corrupted("wrong name of copied child"));
+            }
+            let copied = PlainEntity::new(entity.name.clone(), &entity);
+            copied.check_names()?;
+            clipboard.push(copied);
+        }
+        Ok(clipboard)
     }
 }
 
-// The history of document changes
-struct TransactionStorage {
-    id: property::DocId,
-    htrs: Vec<transaction::Transaction>,
-    /// How many transaction from `htrs` is currently applied to document.
-    /// May be less than size of the vector in case of undo.
-    applied: usize,
-    /// Latest used name of entity created
-    last_id: u32,
+/// The children of the entity are named with their identifiers within the inserted document
+fn has_local_names(entity: &Entity) -> bool {
+    entity
+        .children
+        .iter()
+        .flatten()
+        .all(|child| child.name.len() == 1 && has_local_names(child))
 }
 
-// The document opened in editor
-pub struct Document {
-    /// Document consist of the entities
-    content: Vec<Entity>,
-
-    /// The current active transaction to make changes to this document
-    atrs: transaction::Transaction,
-
-    /// History of changes made to this document
-    my: TransactionStorage,
+/// The key must have the same meaning as where the data was written
+fn check_key_name(types: &TypeRegistry, key: KT, name: &str) -> Result<(), Error> {
+    let known = types.key_by_name(name);
+    if known.is_some_and(|k| k != key) || types.key_name(key).is_some_and(|n| n != name) {
+        return Err(Error::KeyMismatch(key));
+    }
+    Ok(())
+}
 
-    /// Cache of all used documents
-    other: Vec<TransactionStorage>,
+/// What to do with the links from the copied entities to the entities which aren't copied
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExternalLinks {
+    /// The pasted entities link to the original targets
+    Keep,
+    /// The links are removed from the copies
+    Drop,
+    /// The targets are copied too, the pasted entities link to the copies of the targets
+    CopyTarget,
 }
 
-impl Document {
-    pub fn new(id: property::DocId) -> Self {
-        Document {
-            content: vec![],
-            atrs: transaction::Transaction {
-                data: vec![],
-                last_id: Some(vec![START_NAME]),
-            },
-            my: TransactionStorage {
-                id,
-                htrs: vec![],
-                applied: 0,
-                last_id: START_NAME,
-            },
-            other: vec![],
+/// Visit the entities with their full names, the parents before the children
+fn walk<'c>(content: &'c [Entity], parent: &Name, visit: &mut dyn FnMut(Name, &'c Entity)) {
+    for entity in content {
+        let mut name = parent.clone();
+        name.extend(entity.name.last());
+        if let Some(children) = &entity.children {
+            visit(name.clone(), entity);
+            walk(children, &name, visit);
+        } else {
+            visit(name, entity);
         }
     }
+}
 
-    /// Change current document without destroying object
-    pub fn switch(&mut self, id: property::DocId) -> Result<(), &'static str> {
-        match self.other.iter().position(|h| id == h.id) {
-            None => {
-                self.other.push(mem::replace(
-                    &mut self.my,
-                    TransactionStorage {
-                        id,
-                        htrs: vec![],
-                        applied: 0,
-                        last_id: START_NAME,
-                    },
-                ));
-            }
+fn link_target(value: &Value2) -> Option<&Name> {
+    Some(&value.value.downcast_ref::<property::Link>()?.target)
+}
 
-            Some(res) => {
-                mem::swap(&mut self.other[res], &mut self.my);
+/// Properties of the copy of the entity `source` named `copy`,
+/// the links to the copied entities are redirected to their copies
+fn remap_links(
+    props: &[Rc<Value2>],
+    source: &Name,
+    copy: &Name,
+    renamed: &HashMap<&Name, Name>,
+) -> Vec<Rc<Value2>> {
+    let parent = &copy[..copy.len() - 1];
+    props
+        .iter()
+        .map(|prop| {
+            let target = link_target(prop).and_then(|t| renamed.get(&link_target_of(source, t)));
+            // the link is stored relative to the document containing the copy
+            match target.and_then(|t| t.strip_prefix(parent)) {
+                Some(target) => Rc::new(Value2 {
+                    key: prop.key,
+                    value: Box::new(property::Link {
+                        target: target.to_vec(),
+                    }),
+                }),
+                None => prop.clone(),
             }
-        };
+        })
+        .collect()
+}
+
+/// The objects of an application that uses this library should implement this trait
+/// to be notified of the changes of the entities, see Document::observe
+pub trait EntityUser {
+    /// Called after the entity has been changed, `flags` are the CHG_* flags of the change.
+    /// The entity is None if it has been deleted.
+    fn on_change(&mut self, name: &Name, entity: Option<&Entity>, flags: u32);
+}
 
-        self.undo(0)?;
+/// Which changes are reported to an observer
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Observed {
+    /// Changes of all the entities
+    All,
+    /// Changes of the entity with the full name
+    Entity(Name),
+    /// Changes of the property with the key in any entity
+    Key(KT),
+}
 
-        self.atrs.last_id = Some(vec![self.my.last_id]);
+//pub fn entity_user_factory(_entity: &Entity) -> Option<Rc<dyn EntityUser>> {
+//    // detect required object type by the properties and create it
+//    unimplemented!();
+//}
 
-        Ok(())
+/// Find the list of entities which contains the entity with the full name specified
+fn siblings_mut<'c>(content: &'c mut Vec<Entity>, name: &[u32]) -> Option<&'c mut Vec<Entity>> {
+    let (_, parent) = name.split_last()?;
+    let mut list = content;
+    for n in parent {
+        let entity = list.iter_mut().find(|e| e.name.last() == Some(n))?;
+        list = entity.children.as_mut()?;
     }
+    Some(list)
+}
 
-    pub fn entities(&self, with_children: bool) -> EntityIterator {
-        EntityIterator {
-            index: vec![0],
-            with_children,
-            entities: &self.content,
+fn find_entity<'c>(content: &'c [Entity], name: &[u32]) -> Option<&'c Entity> {
+    let (&top_name, rest) = name.split_first()?;
+    content
+        .iter()
+        .find(|e| *e.name.last().unwrap() == top_name)?
+        .get_child(rest.iter())
+}
+
+fn entity_mut<'c>(content: &'c mut Vec<Entity>, name: &[u32]) -> Option<&'c mut Entity> {
+    let last = name.last()?;
+    siblings_mut(content, name)?
+        .iter_mut()
+        .find(|e| e.name.last() == Some(last))
+}
+
+/// The operation which cancels a single modification of the content.
+/// Reverting all the operations recorded while applying a transaction, in reverse order,
+/// returns the content to the state it had before the transaction.
+enum Revert {
+    /// Put back the value of a property which was replaced
+    SetProp {
+        name: Name,
+        pos: usize,
+        value: Rc<Value2>,
+    },
+    /// Put back a property which was deleted
+    InsertProp {
+        name: Name,
+        pos: usize,
+        value: Rc<Value2>,
+    },
+    /// Remove a property which was added
+    RemoveProp { name: Name, pos: usize },
+    /// Restore the children replaced by inserting a document
+    SetChildren {
+        name: Name,
+        children: Option<Vec<Entity>>,
+    },
+    /// Remove an entity which was created
+    RemoveEntity { name: Name },
+    /// Put back an entity which was deleted
+    InsertEntity {
+        name: Name,
+        pos: usize,
+        entity: Entity,
+    },
+}
+
+impl Revert {
+    fn apply(self, content: &mut Vec<Entity>) -> Result<ChangedEntities, Error> {
+        let lost = |name: &Name| Error::EntityNotFound(name.clone());
+        match self {
+            Revert::SetProp { name, pos, value } => {
+                let entity = entity_mut(content, &name).ok_or_else(|| lost(&name))?;
+                let key = value.key;
+                *entity.props2.get_mut(pos).ok_or_else(|| lost(&name))? = value;
+                Ok(ChangedEntities::from(&name, CHG_UPD_PROP, key))
+            }
+            Revert::InsertProp { name, pos, value } => {
+                let entity = entity_mut(content, &name).ok_or_else(|| lost(&name))?;
+                if pos > entity.props2.len() {
+                    return Err(lost(&name));
+                }
+                let key = value.key;
+                entity.props2.push(value);
+                let last = entity.props2.len() - 1;
+                entity.props2.swap(pos, last);
+                Ok(ChangedEntities::from(&name, CHG_ADD_PROP, key))
+            }
+            Revert::RemoveProp { name, pos } => {
+                let entity = entity_mut(content, &name).ok_or_else(|| lost(&name))?;
+                if pos >= entity.props2.len() {
+                    return Err(lost(&name));
+                }
+                let removed = entity.props2.remove(pos);
+                Ok(ChangedEntities::from(&name, CHG_DEL_PROP, removed.key))
+            }
+            Revert::SetChildren { name, children } => {
+                let entity = entity_mut(content, &name).ok_or_else(|| lost(&name))?;
+                let removed = mem::replace(&mut entity.children, children);
+                let mut changes = ChangedEntities::new();
+                for child in removed.iter().flatten() {
+                    changes.add_tree(&name, child, CHG_DELETED);
+                }
+                Ok(changes)
+            }
+            Revert::RemoveEntity { name } => {
+                let list = siblings_mut(content, &name).ok_or_else(|| lost(&name))?;
+                let pos = list
+                    .iter()
+                    .position(|e| e.name.last() == name.last())
+                    .ok_or_else(|| lost(&name))?;
+                let entity = list.remove(pos);
+                let mut changes = ChangedEntities::new();
+                changes.add_tree(&name[..name.len() - 1].to_vec(), &entity, CHG_DELETED);
+                Ok(changes)
+            }
+            Revert::InsertEntity { name, pos, entity } => {
+                let list = siblings_mut(content, &name).ok_or_else(|| lost(&name))?;
+                if pos > list.len() {
+                    return Err(lost(&name));
+                }
+                let mut changes = ChangedEntities::new();
+                changes.add_tree(&name[..name.len() - 1].to_vec(), &entity, CHG_CREATED);
+                list.push(entity);
+                let last = list.len() - 1;
+                list.swap(pos, last);
+                Ok(changes)
+            }
         }
     }
+}
 
-    /// Find entity of (this or inserted) document
-    pub fn get_entity(&self, name: Name) -> Option<&Entity> {
-        let mut name_iter = name.iter();
-        if let Some(&top_name) = name_iter.next() {
-            if let Some(top_index) = self
-                .content
-                .iter()
-                .position(|e| *e.name.last().unwrap() == top_name)
-            {
-                return self.content[top_index].get_child(name_iter);
+/// Transaction of the history which changed an entity, see Document::blame
+pub struct Revision {
+    /// Position of the history right after the transaction, as used by Document::view_at
+    pub pos: usize,
+    pub id: NodeId,
+    pub info: TransactionInfo,
+    /// CHG_* flags of the entity
+    pub flags: u32,
+    pub props: Vec<PropRevision>,
+}
+
+/// Values of a property before and after the transaction, None if the property is absent
+pub struct PropRevision {
+    pub key: KT,
+    pub old: Option<Rc<Value2>>,
+    pub new: Option<Rc<Value2>>,
+}
+
+impl PropRevision {
+    /// Compare the properties of the entity, values are compared by the registry
+    fn diff(
+        types: &TypeRegistry,
+        before: Option<&Entity>,
+        after: Option<&Entity>,
+        key: Option<KT>,
+    ) -> Vec<Self> {
+        let value = |entity: Option<&Entity>, key| entity.and_then(|e| e.get_property_ptr(key));
+        let mut keys: Vec<KT> = vec![];
+        for entity in [before, after].into_iter().flatten() {
+            for prop in &entity.props2 {
+                if !keys.contains(&prop.key) && key.is_none_or(|k| k == prop.key) {
+                    keys.push(prop.key);
+                }
             }
         }
-        None
+
+        keys.into_iter()
+            .map(|key| PropRevision {
+                key,
+                old: value(before, key),
+                new: value(after, key),
+            })
+            .filter(|p| match (&p.old, &p.new) {
+                (Some(old), Some(new)) => !types.eq(old, new),
+                _ => true,
+            })
+            .collect()
     }
 
-    /// This convenient method is useful if all you need to do is read a property
-    pub fn get_property<T: Copy + 'static>(&self, entity_name: Name, key: KT) -> Option<T> {
-        if let Some(entity) = self.get_entity(entity_name) {
-            entity.get_property::<T>(key)
-        } else {
-            None
-        }
+    /// CHG_*_PROP flags of the entity with the changed properties
+    fn flags(props: &[Self]) -> u32 {
+        props.iter().fold(0, |flags, p| {
+            flags
+                | match (&p.old, &p.new) {
+                    (None, _) => CHG_ADD_PROP,
+                    (_, None) => CHG_DEL_PROP,
+                    _ => CHG_UPD_PROP,
+                }
+        })
     }
+}
 
-    pub fn create_entity(&mut self) -> &mut EntityChanges {
-        self.atrs.create_entity()
+/// Change of an entity found by diff_tree
+enum TreeDiff<'a> {
+    Deleted(&'a Entity),
+    Created(&'a Entity),
+    /// The entity exists in both states, the properties differ
+    Changed(Vec<PropRevision>),
+}
+
+/// Compare two states of the list of entities, the children of the entity `parent`.
+/// Entities are matched by the names, values are compared by the registry.
+/// The function gets the full name of every changed entity, a parent precedes its children.
+/// The children of the created and deleted entities aren't compared.
+fn diff_tree<'a>(
+    types: &TypeRegistry,
+    parent: &Name,
+    before: &'a [Entity],
+    after: &'a [Entity],
+    f: &mut dyn FnMut(Name, TreeDiff<'a>),
+) {
+    for old in before {
+        let mut name = parent.clone();
+        name.extend(old.name.last());
+
+        let Some(new) = after.iter().find(|e| e.name.last() == old.name.last()) else {
+            f(name, TreeDiff::Deleted(old));
+            continue;
+        };
+
+        let props = PropRevision::diff(types, Some(old), Some(new), None);
+        if !props.is_empty() {
+            f(name.clone(), TreeDiff::Changed(props));
+        }
+
+        let old_children = old.children.as_deref().unwrap_or_default();
+        let new_children = new.children.as_deref().unwrap_or_default();
+        diff_tree(types, &name, old_children, new_children, f);
+    }
+    for new in after {
+        if !before.iter().any(|e| e.name.last() == new.name.last()) {
+            let mut name = parent.clone();
+            name.extend(new.name.last());
+            f(name, TreeDiff::Created(new));
+        }
     }
+}
 
-    pub fn update_entity(&mut self, name: Name) -> &mut EntityChanges {
-        self.atrs.update_entity(name)
+/// Difference between two states of a document, see Document::diff
+pub struct DocumentDiff {
+    /// Changes of the entities, a change of the parent precedes the changes of its children
+    pub entities: Vec<EntityDiff>,
+}
+
+/// Change of an entity: CHG_CREATED, CHG_DELETED or a combination of the property flags
+pub struct EntityDiff {
+    pub name: Name,
+    pub flags: u32,
+    pub props: Vec<PropRevision>,
+}
+
+impl DocumentDiff {
+    fn new(
+        types: &TypeRegistry,
+        before: &[Entity],
+        after: &[Entity],
+        storages: &mut dyn InsertedStorages,
+    ) -> Result<Self, Error> {
+        let mut res = DocumentDiff { entities: vec![] };
+        res.add(types, &vec![], before, after, storages)?;
+        Ok(res)
     }
 
-    pub fn delete_entity(&mut self, name: Name) {
-        self.atrs.delete_entity(name)
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
     }
 
-    /// Create and return copy of all the entities by its names.
-    /// To make "cut" command, the entities must be deleted just after copying.
-    pub fn copy(&self, names: BTreeSet<Name>) -> Vec<PlainEntity> {
-        // TODO if copied two entity and the first has link to the second, the link should be updated
-        self.entities(true)
-            .filter_map(|e| {
-                if names.contains(&e.name) {
-                    Some(PlainEntity::new(e))
-                } else {
-                    None
+    /// Add the changes turning `before` into `after`, the children of the entity `parent`.
+    /// The children of a created entity inserting a document are compared with the content
+    /// of the inserted document, so only their local overrides are added.
+    fn add(
+        &mut self,
+        types: &TypeRegistry,
+        parent: &Name,
+        before: &[Entity],
+        after: &[Entity],
+        storages: &mut dyn InsertedStorages,
+    ) -> Result<(), Error> {
+        let mut inserting = vec![];
+        diff_tree(types, parent, before, after, &mut |name, diff| {
+            let (flags, props) = match diff {
+                TreeDiff::Deleted(old) => (
+                    CHG_DELETED,
+                    PropRevision::diff(types, Some(old), None, None),
+                ),
+                TreeDiff::Created(new) => {
+                    if let Some(children) = &new.children {
+                        inserting.push((name.clone(), new, children));
+                    }
+                    (
+                        CHG_CREATED,
+                        PropRevision::diff(types, None, Some(new), None),
+                    )
                 }
-            })
-            .collect()
+                TreeDiff::Changed(props) => (PropRevision::flags(&props), props),
+            };
+            self.entities.push(EntityDiff { name, flags, props });
+        });
+
+        for (name, entity, children) in inserting {
+            let inserted = match entity.get_property::<property::DocId>(property::INS_DOC) {
+                Some(doc_id) => Document::inserted_content(types, storages, doc_id)?.0,
+                None => vec![],
+            };
+            self.add(types, &name, &inserted, children, storages)?;
+        }
+        Ok(())
     }
 
-    /// Create in the document copies of entities previously copied with Document::copy.
-    /// The document own all the created entity, even if it was taken from any inserted document.
-    pub fn paste(&mut self, ref clipboard: Vec<PlainEntity>) {
-        let ref mut trs = self.atrs;
-        for entity in clipboard {
-            let changes = trs.create_entity();
+    /// Record the changes into the transaction, so it turns the first state into the second one
+    fn record(&self, trs: &mut transaction::Transaction) {
+        for entity in &self.entities {
+            if entity.flags & CHG_DELETED != 0 {
+                trs.delete_entity(entity.name.clone());
+                continue;
+            }
+
+            let changes = trs.update_entity(entity.name.clone());
             for prop in &entity.props {
-                changes.copy(prop.clone());
+                match &prop.new {
+                    Some(value) => changes.copy(value.clone()),
+                    None => changes.delete(prop.key),
+                };
             }
         }
     }
+}
 
-    pub fn history_size(&self) -> (usize, usize) {
-        (self.my.htrs.len(), self.my.applied)
-    }
+pub struct ChangedEntities {
+    // TODO use transaction::Changes instead
+    pub data: HashMap<Name, u32>,
+    /// Keys of the changed properties of the entities
+    keys: HashMap<Name, BTreeSet<KT>>,
+}
 
-    pub fn undo(&mut self, delta: isize) -> Result<(), &'static str> {
-        let new_pos: usize = (self.my.applied as isize + delta) as usize;
-        if self.my.htrs.len() < new_pos {
-            return Err("undo history overflow");
+impl ChangedEntities {
+    fn new() -> Self {
+        ChangedEntities {
+            data: HashMap::new(),
+            keys: HashMap::new(),
         }
-        if -delta > self.my.applied as isize {
-            return Err("undo history underflow");
+    }
+
+    fn from(name: &Name, flags: u32, key: KT) -> Self {
+        ChangedEntities {
+            data: HashMap::from([(name.clone(), flags)]),
+            keys: HashMap::from([(name.clone(), BTreeSet::from([key]))]),
         }
+    }
 
-        self.content.clear();
-        self.my.applied = 0;
-        for i in 0..new_pos {
-            Document::apply_transaction_private(
-                &self.my.htrs[i],
-                &mut self.content,
-                &mut self.other,
-            )?;
-            self.my.applied += 1;
+    fn add(&mut self, name: &Name, flags: u32) {
+        if let Some(old_flags) = self.data.insert(name.clone(), flags) {
+            self.data.insert(name.clone(), flags | old_flags);
         }
+    }
 
-        Ok(())
+    fn add_keys(&mut self, name: &Name, keys: impl IntoIterator<Item = KT>) {
+        let mut keys = keys.into_iter().peekable();
+        if keys.peek().is_some() {
+            self.keys.entry(name.clone()).or_default().extend(keys);
+        }
     }
 
-    /// Apply all the modifications accumulated in the active transaction to the document and start a new transaction.
-    pub fn commit_transaction(&mut self) -> Result<ChangedEntities, &'static str> {
-        let changes = self.apply_transaction()?;
+    /// Whether the property with the key has been changed in the entity
+    fn has_key(&self, name: &Name, key: KT) -> bool {
+        self.keys.get(name).is_some_and(|keys| keys.contains(&key))
+    }
+
+    fn merge(&mut self, other: Self) {
+        for (name, flags) in other.data {
+            self.add(&name, flags);
+        }
+        for (name, keys) in other.keys {
+            self.add_keys(&name, keys);
+        }
+    }
+
+    /// Mark the entity, which is a child of the entity `parent`, and all its children
+    fn add_tree(&mut self, parent: &Name, entity: &Entity, flags: u32) {
+        let mut name = parent.clone();
+        name.extend(entity.name.last());
+        for child in entity.children.iter().flatten() {
+            self.add_tree(&name, child, flags);
+        }
+        self.add(&name, flags);
+        self.add_keys(&name, entity.props2.iter().map(|p| p.key));
+    }
+
+    /// Convert the names of the inserted document entities into the names in the document
+    fn prefixed(self, parent: &Name) -> Self {
+        let prefix = |name: Name| [parent.as_slice(), name.as_slice()].concat();
+        ChangedEntities {
+            data: self
+                .data
+                .into_iter()
+                .map(|(name, flags)| (prefix(name), flags))
+                .collect(),
+            keys: self
+                .keys
+                .into_iter()
+                .map(|(name, keys)| (prefix(name), keys))
+                .collect(),
+        }
+    }
+
+    /// Find the differences between two states of the same list of entities
+    /// Values are compared by the registry, so an equal value is not reported as changed
+    fn diff(types: &TypeRegistry, before: &[Entity], after: &[Entity]) -> Self {
+        let mut changes = ChangedEntities::new();
+        diff_tree(types, &vec![], before, after, &mut |name, diff| {
+            let parent = &name[..name.len() - 1].to_vec();
+            match diff {
+                TreeDiff::Deleted(old) => changes.add_tree(parent, old, CHG_DELETED),
+                TreeDiff::Created(new) => changes.add_tree(parent, new, CHG_CREATED),
+                TreeDiff::Changed(props) => {
+                    changes.add(&name, PropRevision::flags(&props));
+                    changes.add_keys(&name, props.iter().map(|p| p.key));
+                }
+            }
+        });
+        changes
+    }
+}
+
+/// Histories of the inserted documents
+trait InsertedStorages {
+    /// History of the inserted document, None if the document is treated as empty
+    fn inserted(&mut self, id: property::DocId) -> Option<&TransactionStorage>;
+}
+
+/// The history of a document used for the first time is opened and kept
+impl InsertedStorages for Vec<TransactionStorage> {
+    fn inserted(&mut self, id: property::DocId) -> Option<&TransactionStorage> {
+        match self.iter().position(|h| id == h.id) {
+            None => {
+                self.push(TransactionStorage::new(id));
+                self.last()
+            }
+
+            Some(res) => Some(&self[res]),
+        }
+    }
+}
+
+/// Read-only access to the opened histories, an unknown document is treated as empty
+struct OpenedStorages<'a>(&'a [TransactionStorage]);
+
+impl InsertedStorages for OpenedStorages<'_> {
+    fn inserted(&mut self, id: property::DocId) -> Option<&TransactionStorage> {
+        self.0.iter().find(|h| id == h.id)
+    }
+}
+
+// The history of document changes
+struct TransactionStorage {
+    id: property::DocId,
+    htrs: Vec<transaction::Transaction>,
+    /// How many transaction from `htrs` is currently applied to document.
+    /// May be less than size of the vector in case of undo.
+    applied: usize,
+    /// Latest used name of entity created
+    last_id: u32,
+    /// If Some() every change of the history is written to the file immediately
+    journal: Option<storage::Journal>,
+    /// Copies of the content made at some positions of the history, sorted by the position.
+    /// The snapshot at position 0, if any, keeps the transactions folded by compaction.
+    snapshots: Vec<Snapshot>,
+    /// Operations to undo the latest applied transactions, the last item cancels `htrs[applied - 1]`.
+    /// May contain fewer items than applied transactions, e.g. after the content was restored from a snapshot.
+    reverts: Vec<Vec<Revert>>,
+    /// Branches of the history tree other than `htrs`, left by committing after undo
+    detached: BTreeMap<NodeId, Detached>,
+    /// Identifier of the next transaction committed
+    next_node: NodeId,
+}
+
+/// Content of the document after applying a number of transactions from the history
+struct Snapshot {
+    pos: usize,
+    content: Vec<Entity>,
+}
+
+/// Full name of the link target. The target is named within the document which contains
+/// the linking entity, so the links of an inserted document point to its own entities.
+fn link_target_of(source: &Name, target: &Name) -> Name {
+    let parent = &source[..source.len().saturating_sub(1)];
+    [parent, target.as_slice()].concat()
+}
+
+/// Links between the entities of the document content
+#[derive(Default)]
+struct LinkIndex {
+    /// Link key and target of every link, by the linking entity
+    forward: HashMap<Name, Vec<(KT, Name)>>,
+    /// Linking entity and link key, by the target
+    backward: HashMap<Name, Vec<(Name, KT)>>,
+}
+
+impl LinkIndex {
+    /// Read the links of the changed entities again
+    fn update(&mut self, content: &[Entity], changes: &ChangedEntities) {
+        for name in changes.data.keys() {
+            for (key, target) in self.forward.remove(name).unwrap_or_default() {
+                if let Some(sources) = self.backward.get_mut(&target) {
+                    sources.retain(|(source, k)| source != name || *k != key);
+                    if sources.is_empty() {
+                        self.backward.remove(&target);
+                    }
+                }
+            }
+
+            let Some(entity) = find_entity(content, name) else {
+                continue;
+            };
+            let links: Vec<(KT, Name)> = entity
+                .props2
+                .iter()
+                .filter_map(|p| {
+                    let link = p.value.downcast_ref::<property::Link>()?;
+                    Some((p.key, link_target_of(name, &link.target)))
+                })
+                .collect();
+            for (key, target) in &links {
+                self.backward
+                    .entry(target.clone())
+                    .or_default()
+                    .push((name.clone(), *key));
+            }
+            if !links.is_empty() {
+                self.forward.insert(name.clone(), links);
+            }
+        }
+    }
+}
+
+/// Transaction which isn't on the current path of the history tree
+struct Detached {
+    /// None if the transaction is the first one of its branch
+    parent: Option<NodeId>,
+    trs: transaction::Transaction,
+}
+
+/// Transaction of the history tree, as listed by Document::history_node
+pub struct HistoryNode<'a> {
+    pub id: NodeId,
+    pub parent: Option<NodeId>,
+    pub info: &'a TransactionInfo,
+}
+
+impl TransactionStorage {
+    fn new(id: property::DocId) -> Self {
+        TransactionStorage {
+            id,
+            htrs: vec![],
+            applied: 0,
+            last_id: START_NAME,
+            journal: None,
+            snapshots: vec![],
+            reverts: vec![],
+            detached: BTreeMap::new(),
+            next_node: 0,
+        }
+    }
+
+    fn position(&self, id: NodeId) -> Option<usize> {
+        self.htrs.iter().position(|trs| trs.id == id)
+    }
+
+    /// Move the transactions following the position of the history to the detached branches
+    fn detach(&mut self, pos: usize) {
+        let mut parent = pos.checked_sub(1).map(|p| self.htrs[p].id);
+        for trs in self.htrs.drain(pos..) {
+            let id = trs.id;
+            self.detached.insert(id, Detached { parent, trs });
+            parent = Some(id);
+        }
+        self.snapshots.retain(|s| s.pos <= pos);
+    }
+
+    /// Returns the position where the branch of the node departs from `htrs`
+    /// and the detached transactions of the branch up to the node
+    fn branch_of(&self, id: NodeId) -> Result<(usize, Vec<NodeId>), Error> {
+        if let Some(pos) = self.position(id) {
+            return Ok((pos + 1, vec![]));
+        }
+
+        let mut chain = vec![];
+        let mut node = id;
+        loop {
+            let detached = self.detached.get(&node).ok_or(Error::NodeNotFound(id))?;
+            chain.push(node);
+            let fork = match detached.parent {
+                None => Some(0),
+                Some(parent) => self.position(parent).map(|pos| pos + 1),
+            };
+            if let Some(fork) = fork {
+                chain.reverse();
+                return Ok((fork, chain));
+            }
+            node = detached.parent.unwrap();
+        }
+    }
+
+    /// Make the branch of the node the current path, the transactions following the fork
+    /// mustn't be applied. Returns the position of the node in `htrs`.
+    fn attach(&mut self, id: NodeId) -> Result<usize, Error> {
+        let (fork, chain) = self.branch_of(id)?;
+        if !chain.is_empty() {
+            self.detach(fork);
+            for node in chain {
+                self.htrs.push(self.detached.remove(&node).unwrap().trs);
+            }
+        }
+        Ok(self.position(id).unwrap())
+    }
+
+    /// Forget the transaction with all the transactions made after it
+    fn prune(&mut self, id: NodeId) -> Result<(), Error> {
+        if let Some(pos) = self.position(id) {
+            if pos < self.applied {
+                return Err(Error::NodeApplied(id));
+            }
+            self.detach(pos);
+        } else if !self.detached.contains_key(&id) {
+            return Err(Error::NodeNotFound(id));
+        }
+
+        let mut removed = vec![id];
+        while let Some(node) = removed.pop() {
+            self.detached.remove(&node);
+            removed.extend(
+                self.detached
+                    .iter()
+                    .filter(|(_, d)| d.parent == Some(node))
+                    .map(|(id, _)| *id),
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the latest content saved before the position of the history and
+    /// the position of the first transaction to apply to get the state at the position.
+    fn start_from(&self, pos: usize) -> (Vec<Entity>, usize) {
+        match self.snapshots.iter().rev().find(|s| s.pos <= pos) {
+            Some(snapshot) => (snapshot.content.clone(), snapshot.pos),
+            None => (vec![], 0),
+        }
+    }
+
+    /// Build the content at the position of the history,
+    /// returns it with the operations to undo the transactions applied to the snapshot
+    fn content_at(
+        &self,
+        types: &TypeRegistry,
+        pos: usize,
+        storages: &mut dyn InsertedStorages,
+    ) -> Result<(Vec<Entity>, Vec<Vec<Revert>>), Error> {
+        let (mut content, first) = self.start_from(pos);
+        let mut reverts = vec![];
+        for trs in &self.htrs[first..pos] {
+            let mut ops = vec![];
+            Document::apply_transaction_private(
+                types,
+                &trs.data,
+                &mut content,
+                storages,
+                &mut ops,
+            )?;
+            reverts.push(ops);
+        }
+        Ok((content, reverts))
+    }
+
+    fn write_journal(&mut self, payload: &[u8]) -> Result<(), Error> {
+        if let Some(journal) = &mut self.journal {
+            journal.append(payload)?;
+        }
+        Ok(())
+    }
+
+    /// Repeat a change previously written to the journal
+    fn replay(&mut self, types: &TypeRegistry, mut payload: &[u8]) -> Result<(), Error> {
+        let mut kind = [0u8; 1];
+        payload.read_exact(&mut kind)?;
+        match kind[0] {
+            JOURNAL_COMMIT => {
+                let base = transaction::read_len(&mut payload)?;
+                let mut last_id = [0u8; 4];
+                payload.read_exact(&mut last_id)?;
+                let id = read_node(&mut payload)?;
+                let mut trs = transaction::Transaction::load(types, &mut payload)?;
+                if base > self.htrs.len() {
+                    return Err(Error::JournalMismatch(self.id));
+                }
+                trs.id = id;
+                self.detach(base);
+                self.htrs.push(trs);
+                self.applied = self.htrs.len();
+                self.last_id = u32::from_le_bytes(last_id);
+                self.next_node = self.next_node.max(id + 1);
+            }
+            JOURNAL_UNDO => {
+                let applied = transaction::read_len(&mut payload)?;
+                if applied > self.htrs.len() {
+                    return Err(Error::JournalMismatch(self.id));
+                }
+                self.applied = applied;
+            }
+            JOURNAL_JUMP | JOURNAL_PRUNE => {
+                let id = read_node(&mut payload)?;
+                if kind[0] == JOURNAL_PRUNE {
+                    self.prune(id)?;
+                } else if self.branch_of(id)?.0 < self.applied {
+                    return Err(Error::JournalMismatch(self.id));
+                } else {
+                    self.attach(id)?;
+                }
+            }
+            _ => return Err(Error::Corrupted("unknown kind of journal record")),
+        }
+        Ok(())
+    }
+}
+
+fn read_node(r: &mut dyn Read) -> Result<NodeId, Error> {
+    let mut id = [0u8; 8];
+    r.read_exact(&mut id)?;
+    Ok(NodeId::from_le_bytes(id))
+}
+
+// The document opened in editor
+pub struct Document {
+    /// Document consist of the entities
+    content: Vec<Entity>,
+
+    /// The current active transaction to make changes to this document
+    atrs: transaction::Transaction,
+
+    /// Operations to cancel the changes of the active transaction applied to the content
+    pending: Vec<Revert>,
+
+    /// Count of the changes of the active transaction already applied to the content
+    applied_changes: usize,
+
+    /// Number of the active transaction, distinguishes savepoints of different transactions
+    serial: u64,
+
+    /// History of changes made to this document
+    my: TransactionStorage,
+
+    /// Cache of all used documents
+    other: Vec<TransactionStorage>,
+
+    /// Serialization of the property values, shared with other documents
+    types: Rc<TypeRegistry>,
+
+    /// How many commits are made between snapshots of the content, 0 if snapshots are off
+    snapshot_interval: usize,
+
+    /// Links between the entities of the content
+    links: LinkIndex,
+
+    /// Objects of the application notified of the changes, they aren't owned by the document
+    observers: Vec<(Observed, Weak<RefCell<dyn EntityUser>>)>,
+}
+
+impl Document {
+    pub fn new(id: property::DocId) -> Self {
+        Self::with_types(id, Rc::new(TypeRegistry::new()))
+    }
+
+    /// Create a document which stores and loads property values using the registry specified
+    pub fn with_types(id: property::DocId, types: Rc<TypeRegistry>) -> Self {
+        Document {
+            content: vec![],
+            atrs: transaction::Transaction {
+                data: vec![],
+                last_id: Some(vec![START_NAME]),
+                info: TransactionInfo::default(),
+                id: 0,
+            },
+            pending: vec![],
+            applied_changes: 0,
+            serial: 0,
+            my: TransactionStorage::new(id),
+            other: vec![],
+            types,
+            snapshot_interval: 0,
+            links: LinkIndex::default(),
+            observers: vec![],
+        }
+    }
+
+    pub fn types(&self) -> &Rc<TypeRegistry> {
+        &self.types
+    }
+
+    /// Notify the object after each change of the entities which it observes: after commit, apply,
+    /// undo, redo, rollback and switching the document. The document keeps only a weak reference,
+    /// the observer is forgotten when the application drops it.
+    pub fn observe(&mut self, observed: Observed, user: &Rc<RefCell<dyn EntityUser>>) {
+        self.observers.push((observed, Rc::downgrade(user)));
+    }
+
+    /// Stop notifying the object of all the changes it observes
+    pub fn unobserve(&mut self, user: &Rc<RefCell<dyn EntityUser>>) {
+        let user = Rc::downgrade(user);
+        self.observers.retain(|(_, u)| !u.ptr_eq(&user));
+    }
+
+    /// Report the changes to the observers, the parents before the children
+    fn notify(&mut self, changes: &ChangedEntities) {
+        self.observers.retain(|(_, user)| user.strong_count() > 0);
+        if self.observers.is_empty() {
+            return;
+        }
+
+        let mut names: Vec<(&Name, &u32)> = changes.data.iter().collect();
+        names.sort();
+        for (name, &flags) in names {
+            let entity = find_entity(&self.content, name);
+            for (observed, user) in &self.observers {
+                let interested = match observed {
+                    Observed::All => true,
+                    Observed::Entity(n) => n == name,
+                    Observed::Key(key) => changes.has_key(name, *key),
+                };
+                if let Some(user) = user.upgrade().filter(|_| interested) {
+                    user.borrow_mut().on_change(name, entity, flags);
+                }
+            }
+        }
+    }
+
+    /// Change current document without destroying object.
+    /// The entities of both documents are compared by the names, the entities
+    /// which are absent in the new document are reported as deleted.
+    pub fn switch(&mut self, id: property::DocId) -> Result<ChangedEntities, Error> {
+        if id == self.my.id {
+            return Ok(ChangedEntities::new());
+        }
+
+        match self.other.iter().position(|h| id == h.id) {
+            None => {
+                self.other
+                    .push(mem::replace(&mut self.my, TransactionStorage::new(id)));
+            }
+
+            Some(res) => {
+                mem::swap(&mut self.other[res], &mut self.my);
+            }
+        };
+
+        let changes = self.move_history(0)?;
+
+        self.atrs.last_id = Some(vec![self.my.last_id]);
+
+        self.notify(&changes);
+        Ok(changes)
+    }
+
+    pub fn entities(&self, with_children: bool) -> EntityIterator<'_> {
+        EntityIterator {
+            index: vec![0],
+            with_children,
+            entities: &self.content,
+        }
+    }
+
+    /// Find entity of (this or inserted) document
+    pub fn get_entity(&self, name: Name) -> Option<&Entity> {
+        find_entity(&self.content, &name)
+    }
+
+    /// This convenient method is useful if all you need to do is read a property
+    pub fn get_property<T: Copy + 'static>(&self, entity_name: Name, key: KT) -> Option<T> {
+        self.get_entity(entity_name)?.get_property::<T>(key)
+    }
+
+    /// Transactions of the history which created, changed or deleted the entity, from the oldest one.
+    /// If the key is specified, only the transactions changing the property are listed.
+    /// The history is replayed from the earliest snapshot; the transactions folded by compaction aren't listed.
+    pub fn blame(&self, name: Name, key: Option<KT>) -> Result<Vec<Revision>, Error> {
+        let (mut content, first) = self.my.start_from(0);
+        let mut res = vec![];
+        for (pos, trs) in self.my.htrs.iter().enumerate().skip(first) {
+            let before = find_entity(&content, &name).cloned();
+            let changes = Document::apply_transaction_private(
+                &self.types,
+                &trs.data,
+                &mut content,
+                &mut OpenedStorages(&self.other),
+                &mut vec![],
+            )?;
+            let Some(&flags) = changes.data.get(&name) else {
+                continue;
+            };
+
+            let props = PropRevision::diff(
+                &self.types,
+                before.as_ref(),
+                find_entity(&content, &name),
+                key,
+            );
+            if key.is_some() && props.is_empty() {
+                continue;
+            }
+            res.push(Revision {
+                pos: pos + 1,
+                id: trs.id,
+                info: trs.info.clone(),
+                flags,
+                props,
+            });
+        }
+        Ok(res)
+    }
+
+    /// Entities linking to the entity, with the keys of the link properties
+    pub fn backlinks(&self, target: &Name) -> &[(Name, KT)] {
+        self.links
+            .backward
+            .get(target)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Changes turning this document into the other one, entities are matched by the names.
+    /// Property values are compared by the type registry of this document, see TypeRegistry::eq.
+    pub fn diff(&self, other: &Document) -> Result<DocumentDiff, Error> {
+        DocumentDiff::new(
+            &self.types,
+            &self.content,
+            &other.content,
+            &mut OpenedStorages(&other.other),
+        )
+    }
+
+    /// Changes made to the document between two positions of the history, see Document::view_at
+    pub fn diff_history(&self, from: usize, to: usize) -> Result<DocumentDiff, Error> {
+        let before = self.view_at(from)?;
+        let after = self.view_at(to)?;
+        DocumentDiff::new(
+            &self.types,
+            &before.content,
+            &after.content,
+            &mut OpenedStorages(&self.other),
+        )
+    }
+
+    /// Add the changes of the diff to the active transaction
+    pub fn apply_diff(&mut self, diff: &DocumentDiff) {
+        diff.record(&mut self.atrs);
+
+        // the names of the created entities mustn't be given to new entities again
+        if let Some(last_id) = self.atrs.last_id.as_mut().and_then(|n| n.last_mut()) {
+            for entity in &diff.entities {
+                if let [name] = entity.name[..] {
+                    *last_id = (*last_id).max(name + 1);
+                }
+            }
+        }
+    }
+
+    /// Build the document as it was after `pos` transactions of the history, the content of the document
+    /// isn't touched. The active transaction isn't a part of the view.
+    pub fn view_at(&self, pos: usize) -> Result<DocumentView, Error> {
+        if pos > self.my.htrs.len() {
+            return Err(Error::HistoryOverflow);
+        }
+        let mut storages = OpenedStorages(&self.other);
+        let (content, _) = self.my.content_at(&self.types, pos, &mut storages)?;
+        Ok(DocumentView { content, pos })
+    }
+
+    pub fn create_entity(&mut self) -> &mut EntityChanges {
+        self.atrs.create_entity()
+    }
+
+    pub fn update_entity(&mut self, name: Name) -> &mut EntityChanges {
+        self.atrs.update_entity(name)
+    }
+
+    pub fn delete_entity(&mut self, name: Name) {
+        self.atrs.delete_entity(name)
+    }
+
+    /// Create and return copy of all the entities by its names.
+    /// To make "cut" command, the entities must be deleted just after copying.
+    /// The links to the entities which aren't copied are kept.
+    pub fn copy(&self, names: BTreeSet<Name>) -> Vec<PlainEntity> {
+        self.copy_linked(names, ExternalLinks::Keep)
+    }
+
+    /// Same as Document::copy, the links leaving the copied entities are handled by the policy
+    pub fn copy_linked(
+        &self,
+        mut names: BTreeSet<Name>,
+        external: ExternalLinks,
+    ) -> Vec<PlainEntity> {
+        if external == ExternalLinks::CopyTarget {
+            let mut queue: Vec<Name> = names.iter().cloned().collect();
+            while let Some(name) = queue.pop() {
+                let Some(entity) = find_entity(&self.content, &name) else {
+                    continue;
+                };
+                for target in entity.props2.iter().filter_map(|p| link_target(p)) {
+                    let target = link_target_of(&name, target);
+                    if names.insert(target.clone()) {
+                        queue.push(target);
+                    }
+                }
+            }
+        }
+
+        let mut res: Vec<PlainEntity> = vec![];
+        walk(&self.content, &vec![], &mut |name, entity| {
+            // the children of a copied inserted document are copied with it
+            if names.contains(&name) && !res.iter().any(|e| name.starts_with(&e.name)) {
+                res.push(PlainEntity::new(name, entity));
+            }
+        });
+
+        if external == ExternalLinks::Drop {
+            let mut copied = HashSet::new();
+            for entity in &res {
+                entity.visit(&mut |e| {
+                    copied.insert(e.name.clone());
+                });
+            }
+            for entity in &mut res {
+                entity.visit_mut(&mut |e| {
+                    let name = e.name.clone();
+                    e.props.retain(|p| {
+                        link_target(p).is_none_or(|t| copied.contains(&link_target_of(&name, t)))
+                    });
+                });
+            }
+        }
+        res
+    }
+
+    /// Create in the document copies of entities previously copied with Document::copy.
+    /// The document own all the created entity, even if it was taken from any inserted document.
+    /// The links between the copied entities are redirected to the copies.
+    /// The local overrides of the children of a copied inserted document are made to the copy too.
+    /// Returns the names of the copies in the order of the clipboard.
+    pub fn paste(&mut self, clipboard: Vec<PlainEntity>) -> Result<Vec<Name>, Error> {
+        self.paste_under(&vec![], clipboard)
+    }
+
+    /// Same as Document::paste, but the copies are created as children of the inserted document `parent`.
+    /// The copies are local overrides of the inserted document, they are saved in this document.
+    pub fn paste_into(
+        &mut self,
+        parent: &Name,
+        clipboard: Vec<PlainEntity>,
+    ) -> Result<Vec<Name>, Error> {
+        let entity = find_entity(&self.content, parent)
+            .ok_or_else(|| Error::EntityNotFound(parent.clone()))?;
+        let children = entity
+            .children
+            .as_ref()
+            .ok_or_else(|| Error::NotInsertedDocument(parent.clone()))?;
+
+        // the names of the entities of the inserted document mustn't be given to the copies
+        if let Some(last_id) = self.atrs.last_id.as_mut().and_then(|n| n.last_mut()) {
+            for name in children.iter().filter_map(|e| e.name.last()) {
+                *last_id = (*last_id).max(name + 1);
+            }
+        }
+        self.paste_under(parent, clipboard)
+    }
+
+    fn paste_under(
+        &mut self,
+        parent: &Name,
+        clipboard: Vec<PlainEntity>,
+    ) -> Result<Vec<Name>, Error> {
+        for entity in &clipboard {
+            entity.check_names()?;
+        }
+        let trs = &mut self.atrs;
+        let first = *trs.last_id.as_ref().and_then(|n| n.last()).unwrap();
+        let names: Vec<Name> = (first..)
+            .take(clipboard.len())
+            .map(|id| parent.iter().copied().chain([id]).collect())
+            .collect();
+        // the copies of the children keep their names within the inserted document
+        let mut renamed = HashMap::new();
+        for (entity, name) in clipboard.iter().zip(&names) {
+            entity.visit(&mut |e| {
+                let mut new_name = name.clone();
+                new_name.extend(&e.name[entity.name.len()..]);
+                renamed.insert(&e.name, new_name);
+            });
+        }
+
+        for (entity, name) in clipboard.iter().zip(&names) {
+            let changes = trs.create_entity();
+            changes.ename = name.clone();
+            for prop in remap_links(&entity.props, &entity.name, name, &renamed) {
+                changes.copy(prop);
+            }
+
+            if let Some(children) = &entity.children {
+                // record only the difference from the inserted document, as the overrides were made
+                let doc_id = entity
+                    .props
+                    .iter()
+                    .find(|p| p.key == property::INS_DOC)
+                    .and_then(|p| p.value.downcast_ref::<property::DocId>());
+                let inserted = match doc_id {
+                    Some(&doc_id) => {
+                        Document::inserted_content(&self.types, &mut self.other, doc_id)?.0
+                    }
+                    None => vec![],
+                };
+                let mut children = children.clone();
+                for child in &mut children {
+                    child.visit_mut(&mut |e| {
+                        e.props = remap_links(&e.props, &e.name, &renamed[&e.name], &renamed);
+                    });
+                }
+                let copied: Vec<Entity> = children.iter().map(PlainEntity::to_entity).collect();
+                let mut diff = DocumentDiff { entities: vec![] };
+                diff.add(&self.types, name, &inserted, &copied, &mut self.other)?;
+                diff.record(trs);
+            }
+        }
+        Ok(names)
+    }
+
+    pub fn history_size(&self) -> (usize, usize) {
+        (self.my.htrs.len(), self.my.applied)
+    }
+
+    /// Descriptions of the transactions kept in the history, from the oldest one.
+    /// The first `history_size().1` items are applied, the rest are available for redo.
+    pub fn history(&self) -> impl Iterator<Item = &TransactionInfo> {
+        self.my.htrs.iter().map(|trs| &trs.info)
+    }
+
+    /// The latest applied transaction, None if no transaction is applied
+    pub fn current_node(&self) -> Option<NodeId> {
+        self.my
+            .applied
+            .checked_sub(1)
+            .map(|pos| self.my.htrs[pos].id)
+    }
+
+    /// The transaction of the history tree with its parent
+    pub fn history_node(&self, id: NodeId) -> Option<HistoryNode<'_>> {
+        if let Some(pos) = self.my.position(id) {
+            return Some(HistoryNode {
+                id,
+                parent: pos.checked_sub(1).map(|p| self.my.htrs[p].id),
+                info: &self.my.htrs[pos].info,
+            });
+        }
+        self.my.detached.get(&id).map(|d| HistoryNode {
+            id,
+            parent: d.parent,
+            info: &d.trs.info,
+        })
+    }
+
+    /// The latest transactions of all the branches of the history tree, sorted by the identifiers.
+    /// The branch of the current path is listed as well.
+    pub fn branches(&self) -> Vec<NodeId> {
+        let parents: HashSet<NodeId> = self.my.detached.values().filter_map(|d| d.parent).collect();
+        let mut res: Vec<NodeId> = self
+            .my
+            .detached
+            .keys()
+            .chain(self.my.htrs.last().map(|trs| &trs.id))
+            .filter(|id| !parents.contains(id))
+            .copied()
+            .collect();
+        res.sort();
+        res
+    }
+
+    /// Bring the document to the state right after the transaction, which may belong to any branch.
+    /// The branch of the transaction becomes the current path of the history.
+    pub fn jump(&mut self, id: NodeId) -> Result<ChangedEntities, Error> {
+        let (fork, _) = self.my.branch_of(id)?;
+        // the changes of the active transaction aren't a part of the history
+        let mut changes = self.revert_pending()?;
+        if fork < self.my.applied {
+            changes.merge(self.move_history(fork as isize - self.my.applied as isize)?);
+        }
+
+        let pos = self.my.attach(id)?;
+        self.links.update(&self.content, &changes);
+        if self.my.journal.is_some() {
+            let mut payload = vec![JOURNAL_JUMP];
+            payload.write_all(&id.to_le_bytes())?;
+            self.my.write_journal(&payload)?;
+        }
+
+        let delta = pos + 1 - self.my.applied;
+        if delta > 0 {
+            changes.merge(self.move_history(delta as isize)?);
+        }
+        self.links.update(&self.content, &changes);
+        self.notify(&changes);
+        Ok(changes)
+    }
+
+    /// Remove the transaction with all its descendants from the history tree.
+    /// The applied transactions can't be pruned.
+    pub fn prune(&mut self, id: NodeId) -> Result<(), Error> {
+        self.my.prune(id)?;
+        if self.my.journal.is_some() {
+            let mut payload = vec![JOURNAL_PRUNE];
+            payload.write_all(&id.to_le_bytes())?;
+            self.my.write_journal(&payload)?;
+        }
+        Ok(())
+    }
+
+    /// Description of the active transaction, which is saved to the history on commit
+    pub fn transaction_info(&mut self) -> &mut TransactionInfo {
+        &mut self.atrs.info
+    }
+
+    /// Make a snapshot of the content every `interval` commits, so undo and opening the document
+    /// don't need to replay the history from the very beginning. Zero turns snapshots off.
+    pub fn set_snapshot_interval(&mut self, interval: usize) {
+        self.snapshot_interval = interval;
+    }
+
+    /// Fold the old transactions into a snapshot, keeping only the `undo_depth` latest
+    /// transactions available for undo. Transactions available for redo are kept as well.
+    /// The journal refers to positions of the history; therefore,
+    /// the document should be saved and its journal cleared after compaction.
+    pub fn compact(&mut self, undo_depth: usize) -> Result<(), Error> {
+        let cut = self.my.applied.saturating_sub(undo_depth);
+        if cut == 0 {
+            return Ok(());
+        }
+
+        let (content, _) = self.my.content_at(&self.types, cut, &mut self.other)?;
+
+        // the branches departing from the folded transactions can't be reached anymore
+        let base = self.my.htrs[cut - 1].id;
+        let folded: HashSet<NodeId> = self.my.htrs[..cut].iter().map(|t| t.id).collect();
+        let orphans: Vec<NodeId> = self
+            .my
+            .detached
+            .iter()
+            .filter(|(_, d)| d.parent != Some(base) && d.parent.is_none_or(|p| folded.contains(&p)))
+            .map(|(id, _)| *id)
+            .collect();
+        for detached in self.my.detached.values_mut() {
+            if detached.parent == Some(base) {
+                detached.parent = None;
+            }
+        }
+        for id in orphans {
+            if self.my.detached.contains_key(&id) {
+                self.my.prune(id)?;
+            }
+        }
+
+        self.my.htrs.drain(..cut);
+        self.my.snapshots.retain(|s| s.pos > cut);
+        for snapshot in &mut self.my.snapshots {
+            snapshot.pos -= cut;
+        }
+        self.my.snapshots.insert(0, Snapshot { pos: 0, content });
+        self.my.applied -= cut;
+        let extra = self.my.reverts.len().saturating_sub(self.my.applied);
+        self.my.reverts.drain(..extra);
+        Ok(())
+    }
+
+    /// Write the document with the whole history of changes.
+    /// The active transaction isn't saved.
+    pub fn save_to(&self, w: &mut dyn Write) -> Result<(), Error> {
+        storage::write_header(w)?;
+
+        let mut info = vec![];
+        info.write_all(&self.my.id.to_le_bytes())?;
+        info.write_all(&self.my.last_id.to_le_bytes())?;
+        transaction::write_len(self.my.applied, &mut info)?;
+        transaction::write_len(self.my.htrs.len(), &mut info)?;
+        transaction::write_len(self.my.snapshots.len(), &mut info)?;
+        transaction::write_len(self.my.detached.len(), &mut info)?;
+        info.write_all(&self.my.next_node.to_le_bytes())?;
+        let keys = self.types.key_names();
+        transaction::write_len(keys.len(), &mut info)?;
+        for (key, name) in keys {
+            info.write_all(&key.to_le_bytes())?;
+            name.to_owned().store(&mut info)?;
+        }
+        storage::write_record(&info, w)?;
+
+        for trs in &self.my.htrs {
+            let mut payload = vec![];
+            payload.write_all(&trs.id.to_le_bytes())?;
+            trs.save(&self.types, &mut payload)?;
+            storage::write_record(&payload, w)?;
+        }
+
+        // parents have lower identifiers, so they are written before their children
+        for (id, detached) in &self.my.detached {
+            let mut payload = vec![];
+            payload.write_all(&id.to_le_bytes())?;
+            match detached.parent {
+                Some(parent) => {
+                    payload.write_all(&[1])?;
+                    payload.write_all(&parent.to_le_bytes())?;
+                }
+                None => payload.write_all(&[0])?,
+            }
+            detached.trs.save(&self.types, &mut payload)?;
+            storage::write_record(&payload, w)?;
+        }
+
+        for snapshot in &self.my.snapshots {
+            let mut payload = vec![];
+            transaction::write_len(snapshot.pos, &mut payload)?;
+            Entity::save_all(&snapshot.content, &self.types, &mut payload)?;
+            storage::write_record(&payload, w)?;
+        }
+        Ok(())
+    }
+
+    /// Read the document previously written with Document::save_to and restore its content.
+    pub fn open_from(r: &mut dyn Read, types: Rc<TypeRegistry>) -> Result<Self, Error> {
+        storage::read_header(r)?;
+
+        let info = storage::read_record(r)?;
+        let mut info = info.as_slice();
+        let mut id = [0u8; 4];
+        info.read_exact(&mut id)?;
+        let mut last_id = [0u8; 4];
+        info.read_exact(&mut last_id)?;
+        let applied = transaction::read_len(&mut info)?;
+        let count = transaction::read_len(&mut info)?;
+        let snapshots = transaction::read_len(&mut info)?;
+        let detached = transaction::read_len(&mut info)?;
+        let mut next_node = [0u8; 8];
+        info.read_exact(&mut next_node)?;
+        // the keys must have the same meaning as when the document was written
+        for _ in 0..transaction::read_len(&mut info)? {
+            let mut key = [0u8; 4];
+            info.read_exact(&mut key)?;
+            let key = KT::from_le_bytes(key);
+            let name = String::load(&mut info)?;
+            check_key_name(&types, key, &name)?;
+        }
+        if applied > count {
+            return Err(Error::Corrupted("applied transactions out of history"));
+        }
+
+        let mut doc = Document::with_types(property::DocId::from_le_bytes(id), types);
+        for _ in 0..count {
+            let payload = storage::read_record(r)?;
+            let mut payload = payload.as_slice();
+            let id = read_node(&mut payload)?;
+            let mut trs = transaction::Transaction::load(&doc.types, &mut payload)?;
+            trs.id = id;
+            doc.my.htrs.push(trs);
+        }
+        for _ in 0..detached {
+            let payload = storage::read_record(r)?;
+            let mut payload = payload.as_slice();
+            let id = read_node(&mut payload)?;
+            let mut parent = None;
+            if bool::load(&mut payload)? {
+                let node = read_node(&mut payload)?;
+                if doc.my.position(node).is_none() && !doc.my.detached.contains_key(&node) {
+                    return Err(Error::Corrupted("detached transaction without parent"));
+                }
+                parent = Some(node);
+            }
+            let mut trs = transaction::Transaction::load(&doc.types, &mut payload)?;
+            trs.id = id;
+            doc.my.detached.insert(id, Detached { parent, trs });
+        }
+        doc.my.next_node = NodeId::from_le_bytes(next_node);
+        for _ in 0..snapshots {
+            let payload = storage::read_record(r)?;
+            let mut payload = payload.as_slice();
+            let pos = transaction::read_len(&mut payload)?;
+            if pos > count || doc.my.snapshots.last().is_some_and(|s| s.pos >= pos) {
+                return Err(Error::Corrupted("misplaced snapshot"));
+            }
+            let content = Entity::load_all(&doc.types, &mut payload)?;
+            doc.my.snapshots.push(Snapshot { pos, content });
+        }
+        doc.my.last_id = u32::from_le_bytes(last_id);
+        doc.atrs.last_id = Some(vec![doc.my.last_id]);
+
+        let changes = doc.rebuild(applied)?;
+        doc.links.update(&doc.content, &changes);
+        Ok(doc)
+    }
+
+    /// Write every following commit and undo to the journal file, so they survive a crash.
+    /// The changes already present in the journal are applied to the document first;
+    /// therefore, the document must be in the state it had when the journal was cleared.
+    pub fn open_journal(&mut self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let (journal, records) = storage::Journal::open(path)?;
+        for payload in &records {
+            self.my.replay(&self.types, payload)?;
+        }
+
+        if !records.is_empty() {
+            let changes = self.rebuild(self.my.applied)?;
+            self.links.update(&self.content, &changes);
+            self.atrs.last_id = Some(vec![self.my.last_id]);
+        }
+
+        self.my.journal = Some(journal);
+        Ok(())
+    }
+
+    /// Forget the changes written to the journal, usually after the document has been saved
+    pub fn clear_journal(&mut self) -> Result<(), Error> {
+        if let Some(journal) = &mut self.my.journal {
+            journal.clear()?;
+        }
+        Ok(())
+    }
+
+    /// Cancel `-delta` latest transactions if delta is negative, or redo `delta` transactions.
+    /// Only the changed entities are touched, the way commit does.
+    pub fn undo(&mut self, delta: isize) -> Result<ChangedEntities, Error> {
+        let changes = self.move_history(delta)?;
+        self.notify(&changes);
+        Ok(changes)
+    }
+
+    /// Same as Document::undo, but the observers aren't notified
+    fn move_history(&mut self, delta: isize) -> Result<ChangedEntities, Error> {
+        if -delta > self.my.applied as isize {
+            return Err(Error::HistoryUnderflow);
+        }
+        let new_pos: usize = (self.my.applied as isize + delta) as usize;
+        if self.my.htrs.len() < new_pos {
+            return Err(Error::HistoryOverflow);
+        }
+
+        // the changes of the active transaction aren't a part of the history
+        let mut changes = self.revert_pending()?;
+
+        if delta == 0 {
+            changes.merge(self.rebuild(new_pos)?);
+        }
+        while self.my.applied > new_pos {
+            match self.my.reverts.pop() {
+                Some(ops) => {
+                    for op in ops.into_iter().rev() {
+                        changes.merge(op.apply(&mut self.content)?);
+                    }
+                    self.my.applied -= 1;
+                }
+                None => changes.merge(self.rebuild(new_pos)?),
+            }
+        }
+        while self.my.applied < new_pos {
+            let mut ops = vec![];
+            changes.merge(Document::apply_transaction_private(
+                &self.types,
+                &self.my.htrs[self.my.applied].data,
+                &mut self.content,
+                &mut self.other,
+                &mut ops,
+            )?);
+            self.my.reverts.push(ops);
+            self.my.applied += 1;
+        }
+
+        if delta != 0 && self.my.journal.is_some() {
+            let mut payload = vec![JOURNAL_UNDO];
+            transaction::write_len(self.my.applied, &mut payload)?;
+            self.my.write_journal(&payload)?;
+        }
+
+        self.links.update(&self.content, &changes);
+        Ok(changes)
+    }
+
+    /// Build the content at the position of the history starting from the nearest snapshot
+    fn rebuild(&mut self, pos: usize) -> Result<ChangedEntities, Error> {
+        let (content, reverts) = self.my.content_at(&self.types, pos, &mut self.other)?;
+        let old = mem::replace(&mut self.content, content);
+        self.my.reverts = reverts;
+        self.my.applied = pos;
+        Ok(ChangedEntities::diff(&self.types, &old, &self.content))
+    }
+
+    /// Apply all the modifications accumulated in the active transaction to the document and start a new transaction.
+    /// If the changed entities lack the keys required by the registry or link to missing entities,
+    /// the changes applied by the commit are reverted and the transaction isn't committed,
+    /// so it may be completed and committed again or rolled back.
+    pub fn commit_transaction(&mut self) -> Result<ChangedEntities, Error> {
+        let savepoint = self.savepoint();
+        let changes = self.apply_pending()?;
+        if let Err(e) = self.check_changed(&changes) {
+            self.revert_to(&savepoint)?;
+            return Err(e);
+        }
+
+        if self.atrs.info.timestamp().is_none() {
+            self.atrs.info.set_timestamp(SystemTime::now());
+        }
+        self.atrs.id = self.my.next_node;
+
+        if self.my.journal.is_some() {
+            let payload = self.commit_record()?;
+            self.my.write_journal(&payload)?;
+        }
 
         // save back to document last used entity name
         if let Some(trs_last_id) = &self.atrs.last_id {
@@ -435,98 +2039,333 @@ impl Document {
             }
         }
 
-        // archive the finished transaction and create new
-        let finished = mem::replace(
+        // archive the finished transaction and create new,
+        // the undone transactions are kept as a branch of the history tree
+        let finished = self.start_transaction();
+        self.my.next_node += 1;
+        self.my.detach(self.my.applied);
+        self.my.htrs.push(finished);
+        self.my.applied = self.my.htrs.len();
+        self.my.reverts.push(mem::take(&mut self.pending));
+
+        let applied = self.my.applied;
+        if self.snapshot_interval > 0 && applied.is_multiple_of(self.snapshot_interval) {
+            self.my.snapshots.push(Snapshot {
+                pos: applied,
+                content: self.content.clone(),
+            });
+        }
+
+        self.notify(&changes);
+        Ok(changes)
+    }
+
+    /// Check the entities changed by the active transaction, including the ones changed by the earlier applying
+    fn check_changed(&self, changes: &ChangedEntities) -> Result<(), Error> {
+        let mut names: BTreeSet<&Name> = changes
+            .data
+            .iter()
+            .filter(|(_, flags)| *flags & CHG_DELETED == 0)
+            .map(|(name, _)| name)
+            .collect();
+        for item in &self.atrs.data {
+            if let transaction::Changes::Update(changes) = item {
+                names.insert(&changes.ename);
+            }
+        }
+        for name in names {
+            if let Some(entity) = find_entity(&self.content, name) {
+                if let Some(key) = self.types.missing_key(entity) {
+                    return Err(Error::MissingProperty(name.clone(), key));
+                }
+                for prop in &entity.props2 {
+                    if let Some(link) = prop.value.downcast_ref::<property::Link>() {
+                        if find_entity(&self.content, &link_target_of(name, &link.target)).is_none()
+                        {
+                            return Err(Error::BrokenLink(name.clone(), prop.key));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Discard all the modifications of the active transaction and start a new one.
+    /// The changes already applied to the content are reverted.
+    pub fn rollback_transaction(&mut self) -> Result<ChangedEntities, Error> {
+        let changes = self.revert_pending()?;
+        self.start_transaction();
+        self.links.update(&self.content, &changes);
+        self.notify(&changes);
+        Ok(changes)
+    }
+
+    /// Add to the active transaction the changes of the entities linking to the entities it deletes,
+    /// according to the link policies of the registry
+    fn delete_linking(&mut self) -> Result<(), Error> {
+        let mut deleted: Vec<Name> = self.atrs.data[self.applied_changes..]
+            .iter()
+            .filter_map(|item| match item {
+                transaction::Changes::Delete(name) => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
+
+        let mut next = 0;
+        while next < deleted.len() {
+            let name = deleted[next].clone();
+            next += 1;
+
+            // the children of an inserted document are deleted with it
+            let mut linking: Vec<(Name, Name, KT)> = vec![];
+            for (target, sources) in &self.links.backward {
+                if target.starts_with(&name) {
+                    for (source, key) in sources {
+                        linking.push((target.clone(), source.clone(), *key));
+                    }
+                }
+            }
+            linking.sort();
+
+            for (target, source, key) in linking {
+                if deleted.iter().any(|d| source.starts_with(d)) {
+                    continue;
+                }
+                match self.types.link_policy(key) {
+                    property::LinkPolicy::Reject => {
+                        return Err(Error::EntityLinked(target, source))
+                    }
+                    property::LinkPolicy::Cascade => {
+                        self.atrs.delete_entity(source.clone());
+                        deleted.push(source);
+                    }
+                    property::LinkPolicy::Nullify => {
+                        self.atrs.update_entity(source).delete(key);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Remember the current state of the active transaction to roll back to it later
+    pub fn savepoint(&self) -> Savepoint {
+        Savepoint {
+            serial: self.serial,
+            len: self.atrs.data.len(),
+            last_id: self.atrs.last_id.clone(),
+            applied: self.pending.len(),
+            applied_changes: self.applied_changes,
+        }
+    }
+
+    /// Discard the modifications of the active transaction made after the savepoint,
+    /// the changes applied to the content after the savepoint are reverted.
+    /// The savepoints of committed or rolled back transactions are rejected,
+    /// as well as the ones taken before an undo, a jump or a switch.
+    pub fn rollback_to(&mut self, savepoint: &Savepoint) -> Result<ChangedEntities, Error> {
+        if savepoint.serial != self.serial || savepoint.len > self.atrs.data.len() {
+            return Err(Error::InvalidSavepoint);
+        }
+        let changes = self.revert_to(savepoint)?;
+        self.notify(&changes);
+        Ok(changes)
+    }
+
+    /// Same as Document::rollback_to for a valid savepoint, but the observers aren't notified
+    fn revert_to(&mut self, savepoint: &Savepoint) -> Result<ChangedEntities, Error> {
+        let mut changes = ChangedEntities::new();
+        while self.pending.len() > savepoint.applied {
+            changes.merge(self.pending.pop().unwrap().apply(&mut self.content)?);
+        }
+        self.atrs.rollback_to(savepoint);
+        self.applied_changes = savepoint.applied_changes;
+        self.links.update(&self.content, &changes);
+        Ok(changes)
+    }
+
+    /// Replace the active transaction with an empty one, returns the replaced transaction
+    fn start_transaction(&mut self) -> transaction::Transaction {
+        self.serial += 1;
+        self.applied_changes = 0;
+        mem::replace(
             &mut self.atrs,
             transaction::Transaction {
                 data: vec![],
                 last_id: Some(vec![self.my.last_id]),
+                info: TransactionInfo::default(),
+                id: 0,
             },
-        );
-        self.my.htrs.truncate(self.my.applied);
-        self.my.htrs.push(finished);
-        self.my.applied = self.my.htrs.len();
+        )
+    }
 
+    /// Return the content to the last committed state.
+    /// The savepoints taken before are invalidated, they remember the reverted changes as applied.
+    fn revert_pending(&mut self) -> Result<ChangedEntities, Error> {
+        let mut changes = ChangedEntities::new();
+        self.serial += 1;
+        self.applied_changes = 0;
+        for op in mem::take(&mut self.pending).into_iter().rev() {
+            changes.merge(op.apply(&mut self.content)?);
+        }
         Ok(changes)
     }
 
-    /// Applying without committing is only permitted for specific kinds of modifications
-    pub fn apply_transaction(&mut self) -> Result<ChangedEntities, &'static str> {
-        Document::apply_transaction_private(&self.atrs, &mut self.content, &mut self.other)
+    /// Journal record of the active transaction being committed
+    fn commit_record(&self) -> Result<Vec<u8>, Error> {
+        let mut payload = vec![JOURNAL_COMMIT];
+        transaction::write_len(self.my.applied, &mut payload)?;
+        let last_id = self.atrs.last_id.as_ref().and_then(|n| n.last());
+        payload.write_all(&last_id.unwrap_or(&self.my.last_id).to_le_bytes())?;
+        payload.write_all(&self.atrs.id.to_le_bytes())?;
+        self.atrs.save(&self.types, &mut payload)?;
+        Ok(payload)
+    }
+
+    /// Applying without committing is only permitted for specific kinds of modifications.
+    /// Only the changes made since the previous applying are applied, the entities linking
+    /// to the deleted ones are changed according to the link policies.
+    /// Values of a type other than defined for their keys are rejected before anything is applied,
+    /// the changes are applied all or none.
+    pub fn apply_transaction(&mut self) -> Result<ChangedEntities, Error> {
+        let changes = self.apply_pending()?;
+        self.notify(&changes);
+        Ok(changes)
+    }
+
+    /// Same as Document::apply_transaction, but the observers aren't notified
+    fn apply_pending(&mut self) -> Result<ChangedEntities, Error> {
+        self.delete_linking()?;
+        for item in &self.atrs.data[self.applied_changes..] {
+            if let transaction::Changes::Update(changes) = item {
+                for prop in &changes.props {
+                    // the inserted document is checked when it is opened
+                    if let transaction::PropChange::Update(value) = prop {
+                        if value.key != property::INS_DOC && self.types.check_type(value).is_err() {
+                            return Err(Error::InvalidProperty(changes.ename.clone(), value.key));
+                        }
+                    }
+                }
+            }
+        }
+
+        let reverted = self.pending.len();
+        let applied = Document::apply_transaction_private(
+            &self.types,
+            &self.atrs.data[self.applied_changes..],
+            &mut self.content,
+            &mut self.other,
+            &mut self.pending,
+        );
+        let changes = match applied {
+            Ok(changes) => changes,
+            Err(e) => {
+                for op in self.pending.drain(reverted..).rev() {
+                    op.apply(&mut self.content)?;
+                }
+                return Err(e);
+            }
+        };
+        self.applied_changes = self.atrs.data.len();
+        self.links.update(&self.content, &changes);
+        Ok(changes)
     }
 
     fn apply_transaction_private(
-        trs: &transaction::Transaction,
+        types: &TypeRegistry,
+        data: &[transaction::Changes],
         content: &mut Vec<Entity>,
-        inserted_storages: &mut Vec<TransactionStorage>,
-    ) -> Result<ChangedEntities, &'static str> {
+        inserted_storages: &mut dyn InsertedStorages,
+        reverts: &mut Vec<Revert>,
+    ) -> Result<ChangedEntities, Error> {
         let mut entity_changes = ChangedEntities::new();
-        for item in &trs.data {
+        for item in data {
             match &item {
                 transaction::Changes::Update(changes) => {
                     let chgs = Document::entity_create_or_update(
+                        types,
+                        &changes.ename,
                         changes.ename.iter(),
                         &changes.props,
                         content,
                         inserted_storages,
+                        reverts,
                     )?;
                     entity_changes.merge(chgs);
                 }
                 transaction::Changes::Delete(name) => {
-                    if let Some(last_name) = name.last() {
-                        let entity_pos = content
-                            .iter()
-                            .position(|e| *e.name.last().unwrap() == *last_name);
-                        if let Some(pos) = entity_pos {
-                            content.swap_remove(pos);
-                            entity_changes.add(&name, CHG_DELETED);
-                            return Ok(entity_changes);
-                        }
-                    }
-                    return Err("no suitable object was found");
+                    // the entity may be a child of an inserted document, find it by the full name
+                    let not_found = || Error::EntityNotFound(name.clone());
+                    let siblings = siblings_mut(content, name).ok_or_else(not_found)?;
+                    let pos = siblings
+                        .iter()
+                        .position(|e| e.name.last() == name.last())
+                        .ok_or_else(not_found)?;
+                    let entity = siblings.swap_remove(pos);
+
+                    // the children of the deleted entity are deleted too
+                    entity_changes.add_tree(&name[..name.len() - 1].to_vec(), &entity, CHG_DELETED);
+                    reverts.push(Revert::InsertEntity {
+                        name: name.clone(),
+                        pos,
+                        entity,
+                    });
                 }
             }
         }
         Ok(entity_changes)
     }
 
-    fn get_or_open_transactions<'y>(
-        history: &'y mut Vec<TransactionStorage>,
-        id: property::DocId,
-    ) -> &'y TransactionStorage {
-        match history.iter().position(|h| id == h.id) {
-            None => {
-                history.push(TransactionStorage {
-                    id,
-                    htrs: vec![],
-                    applied: 0,
-                    last_id: START_NAME,
-                });
-                &history.last().unwrap()
-            }
-
-            Some(res) => &history[res],
-        }
+    /// Open the inserted document and apply the transactions from it,
+    /// returns the content which becomes the children of the entity inserting the document
+    fn inserted_content(
+        types: &TypeRegistry,
+        storages: &mut dyn InsertedStorages,
+        doc_id: property::DocId,
+    ) -> Result<(Vec<Entity>, ChangedEntities), Error> {
+        let Some(storage) = storages.inserted(doc_id) else {
+            return Ok((vec![], ChangedEntities::new()));
+        };
+        let (mut content, first) = storage.start_from(storage.applied);
+        let united_trs = transaction::Transaction::merge(&storage.htrs[first..storage.applied]);
+        let changes = Document::apply_transaction_private(
+            types,
+            &united_trs.data,
+            &mut content,
+            storages,
+            &mut vec![],
+        )?;
+        Ok((content, changes))
     }
 
+    /// `full_name` is the name of the entity in the document,
+    /// `ename` iterates over the part of the name which is not yet found in the content
     fn entity_create_or_update(
+        types: &TypeRegistry,
+        full_name: &Name,
         mut ename: std::slice::Iter<u32>,
         props: &Vec<transaction::PropChange>,
         content: &mut Vec<Entity>,
-        storages: &mut Vec<TransactionStorage>,
-    ) -> Result<ChangedEntities, &'static str> {
+        storages: &mut dyn InsertedStorages,
+        reverts: &mut Vec<Revert>,
+    ) -> Result<ChangedEntities, Error> {
         if ename.len() > 1 {
             // for nested entity call this method recursively
             let &last_name = ename.next().unwrap();
             for entity in content.iter_mut() {
                 if *entity.name.last().unwrap() == last_name {
                     if let Some(chlds) = &mut entity.children {
-                        return Self::entity_create_or_update(ename, props, chlds, storages);
+                        return Self::entity_create_or_update(
+                            types, full_name, ename, props, chlds, storages, reverts,
+                        );
                     }
-                    return Err("trying to change a child of an entity without children");
+                    return Err(Error::NotInsertedDocument(
+                        full_name[..full_name.len() - ename.len()].to_vec(),
+                    ));
                 }
             }
-            return Err("entity not found");
+            return Err(Error::EntityNotFound(full_name.clone()));
         }
 
         assert_eq!(ename.len(), 1);
@@ -551,23 +2390,64 @@ impl Document {
                 });
                 object = content.last_mut();
 
-                if let Some(obj) = &object {
-                    entity_changes.add(&obj.name, CHG_CREATED);
-                }
+                reverts.push(Revert::RemoveEntity {
+                    name: full_name.clone(),
+                });
+                entity_changes.add(full_name, CHG_CREATED);
             }
 
             if let Some(entity) = object {
                 // create, change and delete the properties of the entity
 
                 for prop_change in props {
-                    let chg = entity.apply_changes(prop_change, storages)?;
+                    let chg =
+                        entity.apply_changes(types, full_name, prop_change, storages, reverts)?;
                     entity_changes.merge(chg);
                 }
                 return Ok(entity_changes);
             }
         }
 
-        Err("no suitable object was found")
+        Err(Error::EntityNotFound(full_name.clone()))
+    }
+}
+
+impl fmt::Debug for Document {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Document {}:", self.my.id)?;
+        for entity in &self.content {
+            fmt_tree(f, entity, Some(&self.types), 1)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read-only state of the document at a position of the history, see Document::view_at
+pub struct DocumentView {
+    content: Vec<Entity>,
+    pos: usize,
+}
+
+impl DocumentView {
+    /// Count of the transactions applied to get the view
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn entities(&self, with_children: bool) -> EntityIterator<'_> {
+        EntityIterator {
+            index: vec![0],
+            with_children,
+            entities: &self.content,
+        }
+    }
+
+    pub fn get_entity(&self, name: Name) -> Option<&Entity> {
+        find_entity(&self.content, &name)
+    }
+
+    pub fn get_property<T: Copy + 'static>(&self, entity_name: Name, key: KT) -> Option<T> {
+        self.get_entity(entity_name)?.get_property::<T>(key)
     }
 }
 
@@ -595,7 +2475,7 @@ impl<'a> EntityIterator<'a> {
                 return Some(res);
             }
         }
-        return None;
+        None
     }
 }
 
@@ -606,8 +2486,8 @@ impl<'a> Iterator for EntityIterator<'a> {
         let mut res = self.get_entity(self.index.iter());
 
         // increment index
-        if res.is_some() {
-            if self.with_children && res.unwrap().children.is_some() {
+        if let Some(entity) = res {
+            if self.with_children && entity.children.is_some() {
                 self.index.push(0);
             } else {
                 *self.index.last_mut().unwrap() += 1;
@@ -620,8 +2500,8 @@ impl<'a> Iterator for EntityIterator<'a> {
                     *self.index.last_mut().unwrap() += 1;
                     res = self.get_entity(self.index.iter());
 
-                    if res.is_some() {
-                        if res.unwrap().children.is_some() {
+                    if let Some(entity) = res {
+                        if entity.children.is_some() {
                             self.index.push(0);
                         } else {
                             *self.index.last_mut().unwrap() += 1;
@@ -633,7 +2513,7 @@ impl<'a> Iterator for EntityIterator<'a> {
             }
         }
 
-        return res;
+        res
     }
 }
 
@@ -0,0 +1,111 @@
+// Errors reported by the library
+
+use crate::entity::{Name, NodeId};
+use crate::property::{DocId, KT};
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    /// There is no entity with the name
+    EntityNotFound(Name),
+    /// A nested entity is addressed inside an entity which isn't an inserted document
+    NotInsertedDocument(Name),
+    /// The value of the property has a type other than registered for its key
+    TypeMismatch(KT),
+    /// The document names the key differently than the type registry
+    KeyMismatch(KT),
+    /// The entity gets a value of the type other than defined for the key
+    InvalidProperty(Name, KT),
+    /// The entity lacks the key required by the other keys of the entity
+    MissingProperty(Name, KT),
+    /// The entity can't be deleted because the second entity links to it
+    EntityLinked(Name, Name),
+    /// The link property of the entity refers to an entity which doesn't exist
+    BrokenLink(Name, KT),
+    /// The value of the key can't be stored or loaded because its type isn't registered
+    UnknownKey(KT),
+    /// Redo is requested beyond the latest transaction of the history
+    HistoryOverflow,
+    /// Undo is requested beyond the first transaction of the history
+    HistoryUnderflow,
+    /// There is no transaction with the identifier in the history tree
+    NodeNotFound(NodeId),
+    /// The transaction can't be pruned because it is applied to the document
+    NodeApplied(NodeId),
+    /// The savepoint belongs to a transaction which is already finished
+    InvalidSavepoint,
+    /// The journal was written for another state of the document
+    JournalMismatch(DocId),
+    /// The data read is damaged or isn't a document at all
+    Corrupted(&'static str),
+    /// The document is written by an incompatible version of the library
+    UnsupportedVersion(u32),
+    /// Reading or writing failed
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::EntityNotFound(name) => write!(f, "entity {name:?} not found"),
+            Error::NotInsertedDocument(name) => {
+                write!(
+                    f,
+                    "entity {name:?} has no children, it isn't an inserted document"
+                )
+            }
+            Error::TypeMismatch(key) => write!(f, "unexpected type of the property {key} value"),
+            Error::KeyMismatch(key) => {
+                write!(f, "property key {key} has another name in the document")
+            }
+            Error::InvalidProperty(name, key) => {
+                write!(
+                    f,
+                    "entity {name:?} gets a value of wrong type for the property {key}"
+                )
+            }
+            Error::MissingProperty(name, key) => {
+                write!(f, "entity {name:?} lacks the required property {key}")
+            }
+            Error::EntityLinked(target, source) => {
+                write!(f, "entity {target:?} is linked from {source:?}")
+            }
+            Error::BrokenLink(name, key) => {
+                write!(
+                    f,
+                    "entity {name:?} links by the property {key} to a missing entity"
+                )
+            }
+            Error::UnknownKey(key) => write!(f, "no type registered for the property key {key}"),
+            Error::HistoryOverflow => write!(f, "undo history overflow"),
+            Error::HistoryUnderflow => write!(f, "undo history underflow"),
+            Error::NodeNotFound(id) => write!(f, "transaction {id} not found in the history"),
+            Error::NodeApplied(id) => write!(f, "transaction {id} is applied, it can't be pruned"),
+            Error::InvalidSavepoint => write!(f, "savepoint of a finished transaction"),
+            Error::JournalMismatch(id) => {
+                write!(f, "journal doesn't match the state of document {id}")
+            }
+            Error::Corrupted(what) => write!(f, "corrupted data: {what}"),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "unsupported document format version {version}")
+            }
+            Error::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
@@ -1,20 +1,16 @@
 #![allow(dead_code)]
 
 pub mod entity;
+mod error;
 pub mod property;
+pub mod storage;
 pub mod transaction;
 
-#[cfg(test)]
-mod storage;
+pub use error::Error;
 
 #[cfg(test)]
 mod tests {
 
-    use crate::{storage::*, property};
-    use std::{borrow::Cow, str::Bytes};
-    use quick_protobuf::MessageWrite;
-    use std::rc::Rc;
-
 /// [protobuf docs](https://protobuf.dev/programming-guides/proto3/)
 /// [chosen library](https://github.com/tafia/quick-protobuf)
 
@@ -12,10 +12,21 @@ pub type DocId = u32;
 
 // A property linked to an entity
 // A property can store a link to another object to make a graph relationship.
-//pub struct Link {
-//  target: crate::entity::Name,
-//  objects: Vec<crate::entity::Name>,
-//}
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Link {
+    pub target: crate::entity::Name,
+}
+
+/// What happens to the entities linking to an entity being deleted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkPolicy {
+    /// The deletion fails
+    Reject,
+    /// The linking entities are deleted as well
+    Cascade,
+    /// The link property is removed from the linking entities
+    Nullify,
+}
 
 
 // Property value
@@ -0,0 +1,181 @@
+// On-disk representation of the document history
+//
+// A file starts with the header: magic bytes and the format version.
+// The header is followed by records. Each record is its payload length,
+// the payload, and CRC-32 of the payload, so a damaged file is detected while reading.
+// A journal record is preceded by CRC-32 of its length, so a damaged length
+// isn't taken for a record torn by a crash.
+// Copied entities are written the same way with their own magic bytes.
+
+use crate::transaction::{read_len, write_len};
+use crate::Error;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+
+/// The first bytes of every document file
+pub const MAGIC: [u8; 4] = *b"D3S\x1a";
+
+/// The first bytes of the entities written with PlainEntity::save_all
+pub const CLIPBOARD_MAGIC: [u8; 4] = *b"D3C\x1a";
+
+/// Version of the file layout, incremented on every incompatible change
+pub const FORMAT_VERSION: u32 = 6;
+
+pub(crate) fn write_header(w: &mut dyn Write) -> io::Result<()> {
+    w.write_all(&MAGIC)?;
+    w.write_all(&FORMAT_VERSION.to_le_bytes())
+}
+
+pub(crate) fn read_header(r: &mut dyn Read) -> Result<(), Error> {
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(Error::Corrupted("not a document file"));
+    }
+    read_version(r)
+}
+
+pub(crate) fn write_clipboard_header(w: &mut dyn Write) -> io::Result<()> {
+    w.write_all(&CLIPBOARD_MAGIC)?;
+    w.write_all(&FORMAT_VERSION.to_le_bytes())
+}
+
+pub(crate) fn read_clipboard_header(r: &mut dyn Read) -> Result<(), Error> {
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    if magic != CLIPBOARD_MAGIC {
+        return Err(Error::Corrupted("not copied entities"));
+    }
+    read_version(r)
+}
+
+fn read_version(r: &mut dyn Read) -> Result<(), Error> {
+    let mut version = [0u8; 4];
+    r.read_exact(&mut version)?;
+    let version = u32::from_le_bytes(version);
+    if version != FORMAT_VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    Ok(())
+}
+
+pub(crate) fn write_record(payload: &[u8], w: &mut dyn Write) -> io::Result<()> {
+    write_len(payload.len(), w)?;
+    w.write_all(payload)?;
+    w.write_all(&crc32(payload).to_le_bytes())
+}
+
+pub(crate) fn read_record(r: &mut dyn Read) -> Result<Vec<u8>, Error> {
+    let len = read_len(r)?;
+    let mut payload = Vec::new();
+    if r.take(len as u64).read_to_end(&mut payload)? != len {
+        return Err(Error::Io(io::Error::from(ErrorKind::UnexpectedEof)));
+    }
+
+    let mut checksum = [0u8; 4];
+    r.read_exact(&mut checksum)?;
+    if u32::from_le_bytes(checksum) != crc32(&payload) {
+        return Err(Error::Corrupted("record checksum mismatch"));
+    }
+    Ok(payload)
+}
+
+/// CRC-32 (IEEE 802.3) of the data
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Size of the beginning of a journal record: CRC-32 of the payload length and the length
+const JOURNAL_LEN_SIZE: usize = 12;
+
+/// Append-only file of the changes made since the document was last saved.
+/// Every record is flushed to the disk before the change is reported as done,
+/// so a crash loses at most the change being written.
+pub struct Journal {
+    file: File,
+}
+
+impl Journal {
+    /// Open the journal file, or create it if it doesn't exist.
+    /// Returns the journal with the payloads of all the complete records.
+    /// A torn record at the end of the file, left by a crash, is cut off,
+    /// other damaged records are reported as Error::Corrupted.
+    pub fn open(path: impl AsRef<Path>) -> Result<(Journal, Vec<Vec<u8>>), Error> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+        if data.is_empty() {
+            write_header(&mut file)?;
+            file.sync_data()?;
+            return Ok((Journal { file }, vec![]));
+        }
+
+        let mut rest = data.as_slice();
+        read_header(&mut rest)?;
+
+        let mut records = vec![];
+        let mut valid_len = data.len() - rest.len();
+        // only the last record may be torn, it ends before the end of the file
+        while rest.len() >= JOURNAL_LEN_SIZE {
+            let (checksum, len) = rest[..JOURNAL_LEN_SIZE].split_at(4);
+            if u32::from_le_bytes(checksum.try_into().unwrap()) != crc32(len) {
+                return Err(Error::Corrupted("damaged journal record length"));
+            }
+            rest = &rest[4..];
+            match read_record(&mut rest) {
+                Ok(payload) => {
+                    records.push(payload);
+                    valid_len = data.len() - rest.len();
+                }
+                // the length is intact, so the record is really cut off by the end of the file
+                Err(Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(_) => return Err(Error::Corrupted("damaged journal record")),
+            }
+        }
+
+        if valid_len < data.len() {
+            file.set_len(valid_len as u64)?;
+            file.sync_data()?;
+        }
+        Ok((Journal { file }, records))
+    }
+
+    pub(crate) fn append(&mut self, payload: &[u8]) -> io::Result<()> {
+        let mut len = vec![];
+        write_len(payload.len(), &mut len)?;
+        let mut record = crc32(&len).to_le_bytes().to_vec();
+        write_record(payload, &mut record)?;
+        self.file.write_all(&record)?;
+        self.file.sync_data()
+    }
+
+    /// Remove all the records, e.g. after the document has been saved
+    pub(crate) fn clear(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        write_header(&mut self.file)?;
+        self.file.sync_data()
+    }
+}
+
+#[test]
+fn crc32_check_value() {
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+}
@@ -1,23 +1,152 @@
 use crate::entity;
 use crate::property;
-use std::any::Any;
-use std::collections::BTreeMap;
-use std::collections::HashMap;
+use crate::Error;
+use std::any::{Any, TypeId};
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::io;
-use std::io::Error;
 use std::io::ErrorKind;
 use std::io::Read;
 use std::io::Write;
 use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-struct TypeRegistryItem {
-    create: fn(r: &mut dyn Read) -> Option<Box<dyn Any>>,
-    store: fn(&Box<dyn Any>, r: &mut dyn Write) -> Result<(), ()>,
+/// A property value type that can be written to and read from a stream.
+/// Implemented for the common primitive types; the library user implements it for own types.
+pub trait Storable: Any + Sized {
+    fn store(&self, w: &mut dyn Write) -> io::Result<()>;
+    fn load(r: &mut dyn Read) -> io::Result<Self>;
 }
 
-struct TypeRegistry {
+macro_rules! storable_number {
+    ($($t:ty),*) => {$(
+        impl Storable for $t {
+            fn store(&self, w: &mut dyn Write) -> io::Result<()> {
+                w.write_all(&self.to_le_bytes())
+            }
+            fn load(r: &mut dyn Read) -> io::Result<Self> {
+                let mut bytes = [0u8; std::mem::size_of::<$t>()];
+                r.read_exact(&mut bytes)?;
+                Ok(<$t>::from_le_bytes(bytes))
+            }
+        }
+    )*};
+}
+
+storable_number!(i8, u8, i16, u16, i32, u32, i64, u64, f32, f64);
+
+impl Storable for bool {
+    fn store(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(&[*self as u8])
+    }
+    fn load(r: &mut dyn Read) -> io::Result<Self> {
+        Ok(u8::load(r)? != 0)
+    }
+}
+
+impl Storable for String {
+    fn store(&self, w: &mut dyn Write) -> io::Result<()> {
+        write_len(self.len(), w)?;
+        w.write_all(self.as_bytes())
+    }
+    fn load(r: &mut dyn Read) -> io::Result<Self> {
+        let len = read_len(r)?;
+        let mut buf = Vec::new();
+        if r.take(len as u64).read_to_end(&mut buf)? != len {
+            return Err(io::Error::from(ErrorKind::UnexpectedEof));
+        }
+        String::from_utf8(buf).map_err(|_| io::Error::from(ErrorKind::InvalidData))
+    }
+}
+
+impl Storable for property::Link {
+    fn store(&self, w: &mut dyn Write) -> io::Result<()> {
+        write_name(&self.target, w)
+    }
+    fn load(r: &mut dyn Read) -> io::Result<Self> {
+        Ok(property::Link {
+            target: read_name(r)?,
+        })
+    }
+}
+
+pub type CreateFn = fn(r: &mut dyn Read) -> io::Result<Box<dyn Any>>;
+pub type StoreFn = fn(value: &dyn Any, w: &mut dyn Write) -> io::Result<()>;
+pub type EqFn = fn(a: &dyn Any, b: &dyn Any) -> bool;
+pub type HashFn = fn(value: &dyn Any, state: &mut dyn Hasher);
+pub type CmpFn = fn(a: &dyn Any, b: &dyn Any) -> Option<Ordering>;
+pub type FmtFn = fn(value: &dyn Any, f: &mut fmt::Formatter<'_>) -> fmt::Result;
+
+pub struct TypeRegistryItem {
+    create: CreateFn,
+    store: StoreFn,
+}
+
+fn create_value<T: Storable>(r: &mut dyn Read) -> io::Result<Box<dyn Any>> {
+    Ok(Box::new(T::load(r)?))
+}
+
+fn store_value<T: Storable>(value: &dyn Any, w: &mut dyn Write) -> io::Result<()> {
+    match value.downcast_ref::<T>() {
+        Some(v) => v.store(w),
+        None => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "property value type doesn't match the registered one",
+        )),
+    }
+}
+
+fn eq_value<T: PartialEq + 'static>(a: &dyn Any, b: &dyn Any) -> bool {
+    match (a.downcast_ref::<T>(), b.downcast_ref::<T>()) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+fn hash_value<T: Hash + 'static>(value: &dyn Any, mut state: &mut dyn Hasher) {
+    if let Some(value) = value.downcast_ref::<T>() {
+        value.hash(&mut state);
+    }
+}
+
+fn fmt_value<T: fmt::Debug + 'static>(value: &dyn Any, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match value.downcast_ref::<T>() {
+        Some(value) => value.fmt(f),
+        None => entity::fmt_builtin(value, f),
+    }
+}
+
+fn cmp_value<T: PartialOrd + 'static>(a: &dyn Any, b: &dyn Any) -> Option<Ordering> {
+    a.downcast_ref::<T>()?.partial_cmp(b.downcast_ref::<T>()?)
+}
+
+/// Knows how to store and load the value of every property key.
+/// The registry is filled once by the application and shared by all the documents.
+pub struct TypeRegistry {
     // the type of value always defined by key
     all: HashMap<property::KT, TypeRegistryItem>,
+    eq: HashMap<property::KT, EqFn>,
+    hash: HashMap<property::KT, HashFn>,
+    cmp: HashMap<property::KT, CmpFn>,
+    formats: HashMap<property::KT, FmtFn>,
+    keys: HashMap<property::KT, KeyInfo>,
+    by_name: HashMap<String, property::KT>,
+    /// Keys which must be present in every entity having the key
+    required: HashMap<property::KT, Vec<property::KT>>,
+    links: HashMap<property::KT, property::LinkPolicy>,
+}
+
+/// What the registry knows about a property key
+#[derive(Clone, Debug, Default)]
+pub struct KeyInfo {
+    pub name: Option<String>,
+    /// Type of the values, known if the key is registered with a Rust type
+    pub value_type: Option<TypeId>,
+    pub type_name: Option<&'static str>,
+    pub description: Option<String>,
 }
 
 //trait Storable {
@@ -25,8 +154,293 @@ struct TypeRegistry {
 //    fn load<T>(reader: &mut dyn Read) -> Result<T, io::Error>;
 //}
 
+impl Default for TypeRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl TypeRegistry {
-    fn read_key(r: &mut dyn Read) -> Result<property::KT, Error> {
+    /// Create a registry which knows only the keys used by the library itself
+    pub fn new() -> Self {
+        let mut types = TypeRegistry {
+            all: HashMap::new(),
+            eq: HashMap::new(),
+            hash: HashMap::new(),
+            cmp: HashMap::new(),
+            formats: HashMap::new(),
+            keys: HashMap::new(),
+            by_name: HashMap::new(),
+            required: HashMap::new(),
+            links: HashMap::new(),
+        };
+        types
+            .register::<property::DocId>(property::INS_DOC)
+            .register_eq::<property::DocId>(property::INS_DOC)
+            .register_fmt::<property::DocId>(property::INS_DOC)
+            .name_key(property::INS_DOC, "insDoc");
+        types
+    }
+
+    /// Define the type of values stored by the key
+    pub fn register<T: Storable>(&mut self, key: property::KT) -> &mut Self {
+        self.set_type::<T>(key);
+        self.register_fn(key, create_value::<T>, store_value::<T>)
+    }
+
+    /// Define the name and the type of values of the key without defining the serialization
+    pub fn define_key<T: Any>(&mut self, key: property::KT, name: impl Into<String>) -> &mut Self {
+        self.set_type::<T>(key);
+        self.name_key(key, name)
+    }
+
+    fn set_type<T: Any>(&mut self, key: property::KT) {
+        let info = self.keys.entry(key).or_default();
+        info.value_type = Some(TypeId::of::<T>());
+        info.type_name = Some(std::any::type_name::<T>());
+    }
+
+    /// Define the value serialization of the key by a pair of functions
+    pub fn register_fn(
+        &mut self,
+        key: property::KT,
+        create: CreateFn,
+        store: StoreFn,
+    ) -> &mut Self {
+        self.all.insert(key, TypeRegistryItem { create, store });
+        self
+    }
+
+    pub fn is_registered(&self, key: property::KT) -> bool {
+        self.all.contains_key(&key)
+    }
+
+    /// Compare values of the key by the type's equality, hashing and ordering.
+    /// Without the registration two values are equal only if they are the same object.
+    pub fn register_eq<T: PartialEq + PartialOrd + Hash + 'static>(
+        &mut self,
+        key: property::KT,
+    ) -> &mut Self {
+        self.register_eq_fn(
+            key,
+            eq_value::<T>,
+            Some(hash_value::<T>),
+            Some(cmp_value::<T>),
+        )
+    }
+
+    /// Compare values of the key by the type's equality and, optionally, ordering,
+    /// for types which can't be hashed such as floating point numbers
+    pub fn register_partial_eq<T: PartialEq + PartialOrd + 'static>(
+        &mut self,
+        key: property::KT,
+    ) -> &mut Self {
+        self.register_eq_fn(key, eq_value::<T>, None, Some(cmp_value::<T>))
+    }
+
+    /// Define the comparison of the values of the key by functions
+    pub fn register_eq_fn(
+        &mut self,
+        key: property::KT,
+        eq: EqFn,
+        hash: Option<HashFn>,
+        cmp: Option<CmpFn>,
+    ) -> &mut Self {
+        self.eq.insert(key, eq);
+        self.hash.remove(&key);
+        self.cmp.remove(&key);
+        if let Some(hash) = hash {
+            self.hash.insert(key, hash);
+        }
+        if let Some(cmp) = cmp {
+            self.cmp.insert(key, cmp);
+        }
+        self
+    }
+
+    /// Show values of the key by the Debug implementation of the type
+    pub fn register_fmt<T: fmt::Debug + 'static>(&mut self, key: property::KT) -> &mut Self {
+        self.register_fmt_fn(key, fmt_value::<T>)
+    }
+
+    /// Show values of the key by the function
+    pub fn register_fmt_fn(&mut self, key: property::KT, format: FmtFn) -> &mut Self {
+        self.formats.insert(key, format);
+        self
+    }
+
+    /// Give the key a name shown instead of the number.
+    /// The name is taken away from the key which had it before.
+    pub fn name_key(&mut self, key: property::KT, name: impl Into<String>) -> &mut Self {
+        let name = name.into();
+        if let Some(previous) = self.by_name.insert(name.clone(), key) {
+            if previous != key {
+                if let Some(info) = self.keys.get_mut(&previous) {
+                    info.name = None;
+                }
+            }
+        }
+        let info = self.keys.entry(key).or_default();
+        if let Some(old) = info.name.replace(name.clone()) {
+            if old != name {
+                self.by_name.remove(&old);
+            }
+        }
+        self
+    }
+
+    /// Explain the meaning of the key, e.g. for a property editor
+    pub fn describe_key(&mut self, key: property::KT, description: impl Into<String>) -> &mut Self {
+        self.keys.entry(key).or_default().description = Some(description.into());
+        self
+    }
+
+    pub fn key_info(&self, key: property::KT) -> Option<&KeyInfo> {
+        self.keys.get(&key)
+    }
+
+    pub fn key_name(&self, key: property::KT) -> Option<&str> {
+        self.keys.get(&key)?.name.as_deref()
+    }
+
+    pub fn key_by_name(&self, name: &str) -> Option<property::KT> {
+        self.by_name.get(name).copied()
+    }
+
+    /// Named keys sorted by the keys
+    pub(crate) fn key_names(&self) -> Vec<(property::KT, &str)> {
+        let mut res: Vec<_> = self
+            .keys
+            .iter()
+            .filter_map(|(key, info)| Some((*key, info.name.as_deref()?)))
+            .collect();
+        res.sort();
+        res
+    }
+
+    /// Values of the key are links to other entities, the policy defines
+    /// what happens to the linking entity when the target is deleted
+    pub fn register_link(&mut self, key: property::KT, policy: property::LinkPolicy) -> &mut Self {
+        self.links.insert(key, policy);
+        self.register::<property::Link>(key)
+            .register_eq::<property::Link>(key)
+            .register_fmt::<property::Link>(key)
+    }
+
+    /// Deletion of the linked entities is rejected unless another policy is registered for the key
+    pub fn link_policy(&self, key: property::KT) -> property::LinkPolicy {
+        self.links
+            .get(&key)
+            .copied()
+            .unwrap_or(property::LinkPolicy::Reject)
+    }
+
+    /// Every entity having the `kind` key must have the `required` keys as well,
+    /// this is checked on commit
+    pub fn require_keys(&mut self, kind: property::KT, required: &[property::KT]) -> &mut Self {
+        self.required.entry(kind).or_default().extend(required);
+        self
+    }
+
+    /// A key required by the keys of the entity but absent in it
+    pub fn missing_key(&self, entity: &entity::Entity) -> Option<property::KT> {
+        let props = entity.properties();
+        props
+            .iter()
+            .filter_map(|p| self.required.get(&p.key))
+            .flatten()
+            .find(|key| !props.iter().any(|p| p.key == **key))
+            .copied()
+    }
+
+    /// Fails if the type of the value isn't the type defined for its key
+    pub fn check_type(&self, value: &property::Value2) -> Result<(), Error> {
+        let expected = self.keys.get(&value.key).and_then(|info| info.value_type);
+        match expected {
+            Some(expected) if Any::type_id(value.value.as_ref()) != expected => {
+                Err(Error::TypeMismatch(value.key))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Write the key name, or the number if the key has no name, and the value
+    /// by the registered formatter, the values of unregistered keys are formatted if they have a standard type
+    pub fn fmt_value(&self, value: &property::Value2, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.key_name(value.key) {
+            Some(name) => write!(f, "{name}: ")?,
+            None => write!(f, "{}: ", value.key)?,
+        }
+        match self.formats.get(&value.key) {
+            Some(format) => format(value.value.as_ref(), f),
+            None => entity::fmt_builtin(value.value.as_ref(), f),
+        }
+    }
+
+    /// Entity with its children formatted by the registry, e.g. `println!("{:?}", types.debug(entity))`
+    pub fn debug<'a>(&'a self, entity: &'a entity::Entity) -> entity::EntityDebug<'a> {
+        entity::EntityDebug {
+            types: self,
+            entity,
+        }
+    }
+
+    /// Values are equal if they have the same key and either they are the same object
+    /// or the equality registered for the key says so
+    pub fn eq(&self, a: &property::Value2, b: &property::Value2) -> bool {
+        if a.key != b.key {
+            return false;
+        }
+        std::ptr::eq(a, b)
+            || self
+                .eq
+                .get(&a.key)
+                .is_some_and(|eq| eq(a.value.as_ref(), b.value.as_ref()))
+    }
+
+    /// Hash of the key and the value, None if no hashing is registered for the key
+    pub fn hash(&self, value: &property::Value2) -> Option<u64> {
+        let hash = self.hash.get(&value.key)?;
+        let mut state = DefaultHasher::new();
+        value.key.hash(&mut state);
+        hash(value.value.as_ref(), &mut state);
+        Some(state.finish())
+    }
+
+    /// Order of the values of the same key, None if the key has no ordering registered
+    pub fn cmp(&self, a: &property::Value2, b: &property::Value2) -> Option<Ordering> {
+        if a.key != b.key {
+            return None;
+        }
+        (self.cmp.get(&a.key)?)(a.value.as_ref(), b.value.as_ref())
+    }
+
+    pub(crate) fn write_value(
+        &self,
+        pv: &property::Value2,
+        w: &mut dyn Write,
+    ) -> Result<(), Error> {
+        match self.all.get(&pv.key) {
+            Some(td) => {
+                TypeRegistry::write_key(pv.key, w)?;
+                Ok((td.store)(pv.value.as_ref(), w)?)
+            }
+            None => Err(Error::UnknownKey(pv.key)),
+        }
+    }
+
+    pub(crate) fn read_value(&self, r: &mut dyn Read) -> Result<property::Value2, Error> {
+        let key = TypeRegistry::read_key(r)?;
+        match self.all.get(&key) {
+            Some(td) => Ok(property::Value2 {
+                key,
+                value: (td.create)(r)?,
+            }),
+            None => Err(Error::UnknownKey(key)),
+        }
+    }
+
+    fn read_key(r: &mut dyn Read) -> io::Result<property::KT> {
         //let mut str_len_buf = [0u8; 1];
         //r.read_exact(&mut str_len_buf)?;
         //let str_len = str_len_buf[0] as usize + 1;
@@ -39,7 +453,7 @@ impl TypeRegistry {
         if r.read_exact(&mut bytes).is_ok() {
             Ok(property::KT::from_le_bytes(bytes))
         } else {
-            Err(Error::from(ErrorKind::InvalidData))
+            Err(io::Error::from(ErrorKind::InvalidData))
         }
     }
     fn write_key(key: property::KT, w: &mut dyn Write) -> io::Result<()> {
@@ -55,6 +469,33 @@ impl TypeRegistry {
     }
 }
 
+pub(crate) fn write_len(len: usize, w: &mut dyn Write) -> io::Result<()> {
+    w.write_all(&(len as u64).to_be_bytes())
+}
+
+pub(crate) fn read_len(r: &mut dyn Read) -> io::Result<usize> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    usize::try_from(u64::from_be_bytes(buf)).map_err(|_| io::Error::from(ErrorKind::InvalidData))
+}
+
+pub(crate) fn write_name(name: &entity::Name, w: &mut dyn Write) -> io::Result<()> {
+    write_len(name.len(), w)?;
+    for n in name {
+        w.write_all(&n.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+pub(crate) fn read_name(r: &mut dyn Read) -> io::Result<entity::Name> {
+    let len = read_len(r)?;
+    let mut name = entity::Name::with_capacity(len.min(16));
+    for _ in 0..len {
+        name.push(u32::load(r)?);
+    }
+    Ok(name)
+}
+
 #[derive(Clone)]
 pub enum PropChange {
     Update(Rc<property::Value2>),
@@ -69,21 +510,15 @@ pub struct EntityChanges {
 }
 
 impl EntityChanges {
-    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
-        let types = TypeRegistry {
-            all: HashMap::new(),
-        };
-
-        w.write_all(&self.props.len().to_be_bytes())?;
+    pub fn save(&self, types: &TypeRegistry, w: &mut dyn Write) -> Result<(), Error> {
+        write_name(&self.ename, w)?;
+        write_len(self.props.len(), w)?;
 
         for prop_change in &self.props {
             match prop_change {
                 PropChange::Update(rc_value) => {
                     w.write_all(&[1])?;
-                    if let Some(td) = types.all.get(&rc_value.key) {
-                        TypeRegistry::write_key(rc_value.key, w)?;
-                        (td.store)(&rc_value.value, w);
-                    }
+                    types.write_value(rc_value, w)?;
                 }
                 PropChange::Delete(key) => {
                     w.write_all(&[0])?;
@@ -98,55 +533,56 @@ impl EntityChanges {
         Ok(())
     }
 
-    fn load(&mut self, r: &mut dyn Read) -> io::Result<()> {
-        let types = TypeRegistry {
-            all: HashMap::new(),
+    pub fn load(types: &TypeRegistry, r: &mut dyn Read) -> Result<Self, Error> {
+        let mut res = EntityChanges {
+            ename: read_name(r)?,
+            props: vec![],
         };
 
-        let mut buf = [0u8; 8];
-        r.read_exact(&mut buf)?;
-        let count = usize::from_be_bytes(buf);
-
-        //        let h = u64::from_be_bytes(buf);
-
+        let count = read_len(r)?;
         for _ in 0..count {
-            let mut buf= [0u8; 1];
+            let mut buf = [0u8; 1];
             r.read_exact(&mut buf)?;
 
-            let key = TypeRegistry::read_key(r)?;
-            //let key = key_str.as_str();
-            //String::from_utf8(str_buf).map_err(|_| Error::from(ErrorKind::InvalidData))
             match buf[0] {
-                0 => self.props.push(PropChange::Delete(key)),
-                1 => {
-                    if let Some(td) = types.all.get(&key) {
-                        let value = (td.create)(r).ok_or(Error::from(ErrorKind::InvalidData))?;
-                        let pv = property::Value2 { key, value };
-                        self.props.push(PropChange::Update(Rc::new(pv)));
-                    }
-                }
-                _ => {} //{ return Err(Error::from(ErrorKind::InvalidData)); }
+                0 => res
+                    .props
+                    .push(PropChange::Delete(TypeRegistry::read_key(r)?)),
+                1 => res
+                    .props
+                    .push(PropChange::Update(Rc::new(types.read_value(r)?))),
+                _ => return Err(Error::Corrupted("unknown kind of property change")),
             }
         }
-
-        //if let Some(td) = types.all.get(&h) {
-        //    let key = TypeRegistry::read_key(r)?.as_str();
-        //    let value = (td.create)(&mut r);
-        //    e.props2.push(Rc::new(property::Value2 { key, value }));
-        //}
-        Ok(())
+        Ok(res)
     }
 
     /// Add or replace a property
     pub fn add<T: Any>(&mut self, key: property::KT, value: T) -> &mut Self {
         self.props
             .push(PropChange::Update(Rc::new(property::Value2 {
-                key: key,
+                key,
                 value: Box::new(value),
             })));
         self
     }
 
+    /// Same as `add`, but the value is rejected if its type isn't the type defined for the key
+    pub fn try_add<T: Any>(
+        &mut self,
+        types: &TypeRegistry,
+        key: property::KT,
+        value: T,
+    ) -> Result<&mut Self, Error> {
+        let value = property::Value2 {
+            key,
+            value: Box::new(value),
+        };
+        types.check_type(&value)?;
+        self.props.push(PropChange::Update(Rc::new(value)));
+        Ok(self)
+    }
+
     /// Remove a property from the entity
     pub fn delete(&mut self, key: property::KT) -> &mut Self {
         self.props.push(PropChange::Delete(key));
@@ -155,8 +591,8 @@ impl EntityChanges {
 
     /// Copy a property from one entity to the other.
     /// The method designed for handling huge properties, such as images.
-    pub fn copy(&mut self, ref from: Rc<property::Value2>) -> &mut Self {
-        self.props.push(PropChange::Update(from.clone()));
+    pub fn copy(&mut self, from: Rc<property::Value2>) -> &mut Self {
+        self.props.push(PropChange::Update(from));
         self
     }
 }
@@ -173,9 +609,140 @@ pub(crate) struct Transaction {
     pub data: Vec<Changes>,
     /// name for create new object, available only if the transaction is active
     pub last_id: Option<entity::Name>,
+    pub info: TransactionInfo,
+    /// Identifier of the node of the history tree, assigned on commit
+    pub id: entity::NodeId,
+}
+
+/// Description of a transaction shown to the user, e.g. in the Undo menu
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TransactionInfo {
+    label: Option<String>,
+    author: Option<String>,
+    timestamp: Option<SystemTime>,
+    tags: BTreeMap<String, String>,
+}
+
+impl TransactionInfo {
+    /// Human readable name of the transaction, like "Move 3 objects"
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    pub fn author(&self) -> Option<&str> {
+        self.author.as_deref()
+    }
+
+    /// Time of the commit, unless set explicitly before
+    pub fn timestamp(&self) -> Option<SystemTime> {
+        self.timestamp
+    }
+
+    pub fn tags(&self) -> &BTreeMap<String, String> {
+        &self.tags
+    }
+
+    pub fn set_label(&mut self, label: impl Into<String>) -> &mut Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    pub fn set_author(&mut self, author: impl Into<String>) -> &mut Self {
+        self.author = Some(author.into());
+        self
+    }
+
+    pub fn set_timestamp(&mut self, timestamp: SystemTime) -> &mut Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    pub fn add_tag(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        self.tags.insert(key.into(), value.into());
+        self
+    }
+
+    fn save(&self, w: &mut dyn Write) -> Result<(), Error> {
+        write_text(self.label.as_deref(), w)?;
+        write_text(self.author.as_deref(), w)?;
+        match self.timestamp {
+            Some(time) => {
+                // times before the epoch aren't expected, they are stored as the epoch itself
+                let millis = time
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis();
+                w.write_all(&[1])?;
+                w.write_all(&(millis as u64).to_be_bytes())?;
+            }
+            None => w.write_all(&[0])?,
+        }
+        write_len(self.tags.len(), w)?;
+        for (key, value) in &self.tags {
+            key.store(w)?;
+            value.store(w)?;
+        }
+        Ok(())
+    }
+
+    fn load(r: &mut dyn Read) -> Result<Self, Error> {
+        let mut res = TransactionInfo {
+            label: read_text(r)?,
+            author: read_text(r)?,
+            ..Default::default()
+        };
+        if bool::load(r)? {
+            let mut millis = [0u8; 8];
+            r.read_exact(&mut millis)?;
+            res.timestamp = Some(UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(millis)));
+        }
+        for _ in 0..read_len(r)? {
+            let key = String::load(r)?;
+            res.tags.insert(key, String::load(r)?);
+        }
+        Ok(res)
+    }
+}
+
+fn write_text(text: Option<&str>, w: &mut dyn Write) -> io::Result<()> {
+    match text {
+        Some(text) => {
+            w.write_all(&[1])?;
+            write_len(text.len(), w)?;
+            w.write_all(text.as_bytes())
+        }
+        None => w.write_all(&[0]),
+    }
+}
+
+fn read_text(r: &mut dyn Read) -> io::Result<Option<String>> {
+    if bool::load(r)? {
+        Ok(Some(String::load(r)?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// A point inside the active transaction, which the transaction may be rolled back to
+pub struct Savepoint {
+    /// Number of the transaction started by the document
+    pub(crate) serial: u64,
+    /// Count of changes made before the savepoint
+    pub(crate) len: usize,
+    pub(crate) last_id: Option<entity::Name>,
+    /// Count of applied changes which must be kept on rolling back
+    pub(crate) applied: usize,
+    /// Count of the items of the transaction applied before the savepoint
+    pub(crate) applied_changes: usize,
 }
 
 impl Transaction {
+    /// Forget the changes made after the savepoint
+    pub fn rollback_to(&mut self, savepoint: &Savepoint) {
+        self.data.truncate(savepoint.len);
+        self.last_id = savepoint.last_id.clone();
+    }
+
     pub fn create_entity(&mut self) -> &mut EntityChanges {
         let name = self.last_id.as_ref().unwrap().clone();
 
@@ -202,10 +769,54 @@ impl Transaction {
         self.data.push(Changes::Delete(name));
     }
 
-    pub fn merge(transactions: &Vec<Transaction>) -> Transaction {
+    pub fn save(&self, types: &TypeRegistry, w: &mut dyn Write) -> Result<(), Error> {
+        self.info.save(w)?;
+        write_len(self.data.len(), w)?;
+        for item in &self.data {
+            match item {
+                Changes::Update(changes) => {
+                    w.write_all(&[1])?;
+                    changes.save(types, w)?;
+                }
+                Changes::Delete(name) => {
+                    w.write_all(&[0])?;
+                    write_name(name, w)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load(types: &TypeRegistry, r: &mut dyn Read) -> Result<Self, Error> {
+        let mut res = Transaction {
+            data: vec![],
+            last_id: None,
+            info: TransactionInfo::load(r)?,
+            id: 0,
+        };
+
+        let count = read_len(r)?;
+        for _ in 0..count {
+            let mut buf = [0u8; 1];
+            r.read_exact(&mut buf)?;
+
+            match buf[0] {
+                0 => res.data.push(Changes::Delete(read_name(r)?)),
+                1 => res
+                    .data
+                    .push(Changes::Update(EntityChanges::load(types, r)?)),
+                _ => return Err(Error::Corrupted("unknown kind of entity change")),
+            }
+        }
+        Ok(res)
+    }
+
+    pub fn merge(transactions: &[Transaction]) -> Transaction {
         let mut res = Transaction {
             data: vec![],
             last_id: None,
+            info: TransactionInfo::default(),
+            id: 0,
         };
 
         //    let mut iter: Option<core::slice::Iter<Changes>> = None;
@@ -220,7 +831,7 @@ impl Transaction {
             // to do sort changes properly
         }
 
-        return res;
+        res
     }
 
     // count all the changes in the transaction, useful for detect new changes
@@ -236,6 +847,6 @@ impl Transaction {
                 }
             }
         }
-        return changes_count;
+        changes_count
     }
 }
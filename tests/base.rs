@@ -6,6 +6,7 @@ use d3s::property::{DocId, INS_DOC, KT};
 
 pub const COLOR: KT = 101; //"color";
 pub const TITLE: KT = 102; //"title";
+pub const WIDTH: KT = 103;
 
 #[test]
 fn create() {
@@ -53,6 +54,36 @@ fn create_two() {
     assert_eq!(doc.entities(false).count(), 2);
 }
 
+#[test]
+fn apply_then_commit() {
+    let mut doc = Document::new(1);
+    doc.create_entity().add(COLOR, 1);
+    doc.create_entity().add(COLOR, 2);
+    assert!(doc.commit_transaction().is_ok());
+
+    // the applied changes aren't applied again
+    doc.delete_entity(vec![START_NAME]);
+    assert!(doc.apply_transaction().is_ok());
+    assert!(doc.apply_transaction().unwrap().data.is_empty());
+    doc.update_entity(vec![START_NAME + 1]).add(COLOR, 3);
+    let changes = doc.commit_transaction().unwrap();
+    assert_eq!(changes.data.len(), 1);
+    assert_eq!(doc.entities(false).count(), 1);
+
+    // a failed applying leaves nothing applied
+    doc.update_entity(vec![START_NAME + 1]).add(COLOR, 4);
+    doc.delete_entity(vec![START_NAME]);
+    assert!(doc.apply_transaction().is_err());
+    assert_eq!(
+        doc.get_property::<i32>(vec![START_NAME + 1], COLOR),
+        Some(3)
+    );
+    assert!(doc.rollback_transaction().is_ok());
+
+    assert!(doc.undo(-1).is_ok());
+    assert_eq!(doc.entities(false).count(), 2);
+}
+
 #[test]
 fn duplicate_property() {
     let mut doc = Document::new(1);
@@ -119,7 +150,7 @@ fn delete_property() {
 fn undo_redo() {
     let mut doc = Document::new(1);
     assert_eq!(doc.history_size(), (0, 0));
-    assert!(doc.undo(-1).is_ok() == false);
+    assert!(doc.undo(-1).is_err());
 
     {
         doc.create_entity().add(COLOR, 101);
@@ -146,7 +177,7 @@ fn undo_redo() {
         102
     );
     assert_eq!(doc.history_size(), (2, 2));
-    assert!(doc.undo(1).is_ok() == false);
+    assert!(doc.undo(1).is_err());
 }
 
 #[test]
@@ -349,14 +380,55 @@ fn delete_inserted_entity() {
     assert!(doc.commit_transaction().is_ok());
 
     doc.delete_entity(vec![START_NAME, START_NAME]);
-    assert!(doc.commit_transaction().is_ok());
+    let changes = doc.commit_transaction().unwrap();
+    assert_eq!(changes.data.len(), 1);
+    assert!(changes.data.contains_key(&vec![START_NAME, START_NAME]));
 
     // Entity deleted only in the active document, original one remain unchanged
-    assert_eq!(doc.entities(true).count(), 0);
+    assert_eq!(doc.entities(true).count(), 1);
+    assert!(doc.get_entity(vec![START_NAME]).is_some());
+    assert!(doc.get_entity(vec![START_NAME, START_NAME]).is_none());
     assert!(doc.switch(222).is_ok());
     assert_eq!(doc.entities(true).count(), 1);
 }
 
+#[test]
+fn delete_several_entities() {
+    let mut doc = Document::new(222);
+    doc.create_entity().add(COLOR, 21);
+    doc.create_entity().add(COLOR, 22);
+    assert!(doc.commit_transaction().is_ok());
+
+    assert!(doc.switch(111).is_ok());
+    doc.create_entity().add(INS_DOC, 222 as DocId);
+    doc.create_entity().add(COLOR, 1);
+    doc.create_entity().add(COLOR, 2);
+    assert!(doc.commit_transaction().is_ok());
+
+    // the changes following a deletion are applied as well
+    doc.delete_entity(vec![START_NAME + 1]);
+    doc.delete_entity(vec![START_NAME, START_NAME + 1]);
+    doc.update_entity(vec![START_NAME + 2]).add(COLOR, 3);
+    assert!(doc.commit_transaction().is_ok());
+    assert!(doc.get_entity(vec![START_NAME + 1]).is_none());
+    assert!(doc.get_entity(vec![START_NAME, START_NAME + 1]).is_none());
+    assert!(doc.get_entity(vec![START_NAME, START_NAME]).is_some());
+    assert_eq!(
+        doc.get_property::<i32>(vec![START_NAME + 2], COLOR),
+        Some(3)
+    );
+
+    // deletion of an inserted document is reported for its children too
+    doc.delete_entity(vec![START_NAME]);
+    let changes = doc.commit_transaction().unwrap();
+    assert_eq!(changes.data.len(), 2);
+    assert!(changes.data.contains_key(&vec![START_NAME, START_NAME]));
+    assert_eq!(doc.entities(true).count(), 1);
+
+    assert!(doc.undo(-2).is_ok());
+    assert_eq!(doc.entities(true).count(), 5);
+}
+
 #[test]
 fn clipboard() {
     let mut doc = Document::new(222);
@@ -372,7 +444,623 @@ fn clipboard() {
 
     let selected = BTreeSet::from([vec![START_NAME + 1], vec![START_NAME, START_NAME]]);
     let clipboard = doc.copy(selected);
-    doc.paste(clipboard);
+    assert!(doc.paste(clipboard).is_ok());
+    assert!(doc.commit_transaction().is_ok());
+    assert_eq!(doc.entities(true).count(), 6);
+}
+
+#[test]
+fn paste_into() {
+    let mut doc = Document::new(222);
+    doc.create_entity().add(COLOR, 11);
+    doc.create_entity().add(COLOR, 22);
+    assert!(doc.commit_transaction().is_ok());
+
+    assert!(doc.switch(111).is_ok());
+    doc.create_entity().add(INS_DOC, 222 as DocId);
+    doc.create_entity().add(COLOR, 33);
+    assert!(doc.commit_transaction().is_ok());
+
+    // the copy doesn't take the name of an entity of the inserted document
+    let inserted = vec![START_NAME];
+    let clipboard = doc.copy(BTreeSet::from([vec![START_NAME + 1]]));
+    let names = doc.paste_into(&inserted, clipboard.clone()).unwrap();
+    assert_eq!(names, [vec![START_NAME, START_NAME + 2]]);
+    assert!(doc.commit_transaction().is_ok());
+    assert_eq!(doc.get_property::<i32>(names[0].clone(), COLOR), Some(33));
+    assert_eq!(doc.entities(true).count(), 5);
+
+    assert_eq!(
+        doc.paste(clipboard.clone()).unwrap(),
+        [vec![START_NAME + 3]]
+    );
+    assert!(doc.commit_transaction().is_ok());
+
+    assert!(matches!(
+        doc.paste_into(&vec![START_NAME + 1], clipboard.clone()),
+        Err(d3s::Error::NotInsertedDocument(_))
+    ));
+    assert!(matches!(
+        doc.paste_into(&vec![START_NAME + 9], clipboard),
+        Err(d3s::Error::EntityNotFound(_))
+    ));
+}
+
+#[test]
+fn copy_inserted() {
+    let mut doc = Document::new(222);
+    doc.create_entity().add(COLOR, 11);
+    doc.create_entity().add(COLOR, 22);
+    assert!(doc.commit_transaction().is_ok());
+
+    assert!(doc.switch(111).is_ok());
+    doc.create_entity().add(INS_DOC, 222 as DocId);
+    assert!(doc.commit_transaction().is_ok());
+    doc.update_entity(vec![START_NAME, START_NAME])
+        .add(COLOR, 44);
+    doc.delete_entity(vec![START_NAME, START_NAME + 1]);
+    doc.update_entity(vec![START_NAME, START_NAME + 5])
+        .add(COLOR, 55);
+    assert!(doc.commit_transaction().is_ok());
+
+    // the selected child is copied as a part of the inserted document
+    let selected = BTreeSet::from([vec![START_NAME], vec![START_NAME, START_NAME]]);
+    let clipboard = doc.copy(selected);
+    assert_eq!(clipboard.len(), 1);
+    assert_eq!(clipboard[0].children.as_ref().map(Vec::len), Some(2));
+
+    let copy = doc.paste(clipboard).unwrap().remove(0);
     assert!(doc.commit_transaction().is_ok());
+    let child = |id: u32| [copy.clone(), vec![id]].concat();
+    assert_eq!(doc.get_property::<i32>(child(START_NAME), COLOR), Some(44));
+    assert!(doc.get_entity(child(START_NAME + 1)).is_none());
+    assert_eq!(
+        doc.get_property::<i32>(child(START_NAME + 5), COLOR),
+        Some(55)
+    );
     assert_eq!(doc.entities(true).count(), 6);
 }
+
+#[test]
+fn links_in_inserted_document() {
+    use d3s::property::{Link, LinkPolicy};
+
+    const HOST: KT = 201;
+    let mut types = d3s::transaction::TypeRegistry::new();
+    types.register_link(HOST, LinkPolicy::Cascade);
+    let mut doc = Document::with_types(2, std::rc::Rc::new(types));
+    doc.create_entity().add(TITLE, "Wall");
+    doc.create_entity().add(
+        HOST,
+        Link {
+            target: vec![START_NAME],
+        },
+    );
+    assert!(doc.commit_transaction().is_ok());
+
+    // the links of the inserted document point to its own entities
+    assert!(doc.switch(3).is_ok());
+    doc.create_entity().add(INS_DOC, 2 as DocId);
+    assert!(doc.commit_transaction().is_ok());
+    let inserted = START_NAME;
+    doc.create_entity().add(TITLE, "Own");
+    assert!(doc.commit_transaction().is_ok());
+    assert_eq!(
+        doc.backlinks(&vec![inserted, START_NAME]),
+        [(vec![inserted, START_NAME + 1], HOST)]
+    );
+    assert!(doc.backlinks(&vec![START_NAME + 1]).is_empty());
+
+    doc.delete_entity(vec![START_NAME + 1]);
+    assert!(doc.commit_transaction().is_ok());
+    assert!(doc.get_entity(vec![inserted, START_NAME + 1]).is_some());
+
+    doc.delete_entity(vec![inserted, START_NAME]);
+    assert!(doc.commit_transaction().is_ok());
+    assert!(doc.get_entity(vec![inserted, START_NAME + 1]).is_none());
+}
+
+#[test]
+fn copy_links() {
+    use d3s::entity::ExternalLinks;
+    use d3s::property::{Link, LinkPolicy};
+
+    const HOST: KT = 201;
+    const NOTE: KT = 203;
+    let mut types = d3s::transaction::TypeRegistry::new();
+    types
+        .register_link(HOST, LinkPolicy::Cascade)
+        .register_link(NOTE, LinkPolicy::Nullify);
+    let mut doc = Document::with_types(1, std::rc::Rc::new(types));
+    let wall = START_NAME;
+    let link = |name: u32| Link { target: vec![name] };
+    doc.create_entity().add(TITLE, "Wall");
+    doc.create_entity()
+        .add(HOST, link(wall))
+        .add(NOTE, link(wall + 2));
+    doc.create_entity().add(TITLE, "Note");
+    assert!(doc.commit_transaction().is_ok());
+
+    let target = |doc: &Document, name: u32, key: KT| {
+        let prop = doc.get_entity(vec![name])?.get_property_ptr(key)?;
+        Some(prop.value.downcast_ref::<Link>()?.target.clone())
+    };
+    let selected = BTreeSet::from([vec![wall], vec![wall + 1]]);
+
+    // the copied door is hosted by the copied wall and keeps the note
+    assert!(doc.paste(doc.copy(selected.clone())).is_ok());
+    assert!(doc.commit_transaction().is_ok());
+    assert_eq!(target(&doc, wall + 4, HOST), Some(vec![wall + 3]));
+    assert_eq!(target(&doc, wall + 4, NOTE), Some(vec![wall + 2]));
+
+    assert!(doc
+        .paste(doc.copy_linked(selected.clone(), ExternalLinks::Drop))
+        .is_ok());
+    assert!(doc.commit_transaction().is_ok());
+    assert_eq!(target(&doc, wall + 6, HOST), Some(vec![wall + 5]));
+    assert_eq!(target(&doc, wall + 6, NOTE), None);
+
+    assert!(doc
+        .paste(doc.copy_linked(selected, ExternalLinks::CopyTarget))
+        .is_ok());
+    assert!(doc.commit_transaction().is_ok());
+    assert_eq!(doc.entities(false).count(), 10);
+    assert_eq!(target(&doc, wall + 8, HOST), Some(vec![wall + 7]));
+    assert_eq!(target(&doc, wall + 8, NOTE), Some(vec![wall + 9]));
+}
+
+#[test]
+fn copy_inserted_links() {
+    use d3s::entity::ExternalLinks;
+    use d3s::property::{Link, LinkPolicy};
+
+    const HOST: KT = 201;
+    const NOTE: KT = 203;
+    let mut types = d3s::transaction::TypeRegistry::new();
+    types
+        .register_link(HOST, LinkPolicy::Cascade)
+        .register_link(NOTE, LinkPolicy::Nullify);
+    let mut doc = Document::with_types(2, std::rc::Rc::new(types));
+    let link = |name: u32| Link { target: vec![name] };
+    let wall = START_NAME;
+    doc.create_entity().add(TITLE, "Wall");
+    doc.create_entity().add(HOST, link(wall));
+    assert!(doc.commit_transaction().is_ok());
+
+    assert!(doc.switch(3).is_ok());
+    doc.create_entity().add(INS_DOC, 2 as DocId);
+    doc.create_entity().add(TITLE, "Wall");
+    doc.create_entity().add(HOST, link(wall + 1));
+    assert!(doc.commit_transaction().is_ok());
+    let inserted = START_NAME;
+    doc.update_entity(vec![inserted, wall + 1])
+        .add(NOTE, link(wall));
+    assert!(doc.commit_transaction().is_ok());
+
+    // the links of the children stay inside the copy of the inserted document
+    let selected = BTreeSet::from([vec![inserted]]);
+    let copy = doc
+        .paste(doc.copy_linked(selected, ExternalLinks::Drop))
+        .unwrap()
+        .remove(0);
+    assert!(doc.commit_transaction().is_ok());
+    let mut links = doc.backlinks(&vec![copy[0], wall]).to_vec();
+    links.sort();
+    assert_eq!(
+        links,
+        [
+            (vec![copy[0], wall + 1], HOST),
+            (vec![copy[0], wall + 1], NOTE)
+        ]
+    );
+
+    // the links between the copies pasted into an inserted document are relative to it
+    let selected = BTreeSet::from([vec![wall + 1], vec![wall + 2]]);
+    let names = doc.paste_into(&vec![inserted], doc.copy(selected)).unwrap();
+    assert!(doc.commit_transaction().is_ok());
+    assert_eq!(doc.backlinks(&names[0]), [(names[1].clone(), HOST)]);
+}
+
+#[test]
+fn errors() {
+    let mut doc = Document::new(222);
+    doc.create_entity().add(COLOR, 2);
+    assert!(doc.commit_transaction().is_ok());
+    assert!(doc.switch(111).is_ok());
+    doc.create_entity().add(INS_DOC, 222 as DocId);
+    doc.create_entity().add(COLOR, 1);
+    assert!(doc.commit_transaction().is_ok());
+
+    assert!(matches!(doc.undo(1), Err(d3s::Error::HistoryOverflow)));
+    assert!(matches!(doc.undo(-2), Err(d3s::Error::HistoryUnderflow)));
+
+    doc.delete_entity(vec![START_NAME + 5]);
+    match doc.commit_transaction() {
+        Err(d3s::Error::EntityNotFound(name)) => assert_eq!(name, vec![START_NAME + 5]),
+        _ => panic!("deleting a missing entity must fail"),
+    }
+
+    let mut doc = Document::new(111);
+    doc.create_entity().add(COLOR, 1);
+    assert!(doc.commit_transaction().is_ok());
+    doc.update_entity(vec![START_NAME, START_NAME])
+        .add(COLOR, 2);
+    match doc.commit_transaction() {
+        Err(d3s::Error::NotInsertedDocument(name)) => assert_eq!(name, vec![START_NAME]),
+        _ => panic!("an entity without children can't be changed as an inserted document"),
+    }
+
+    let mut doc = Document::new(111);
+    doc.create_entity().add(INS_DOC, "222");
+    assert!(matches!(
+        doc.commit_transaction(),
+        Err(d3s::Error::TypeMismatch(INS_DOC))
+    ));
+}
+
+#[test]
+fn rollback() {
+    let mut doc = Document::new(1);
+    doc.create_entity().add(COLOR, 1);
+    assert!(doc.commit_transaction().is_ok());
+
+    doc.update_entity(vec![START_NAME])
+        .add(COLOR, 2)
+        .add(TITLE, "qwerty");
+    assert!(doc.apply_transaction().is_ok());
+    doc.create_entity().add(COLOR, 3);
+    assert!(doc.apply_transaction().is_ok());
+    doc.delete_entity(vec![START_NAME]);
+    assert!(doc.apply_transaction().is_ok());
+    assert_eq!(doc.entities(false).count(), 1);
+
+    let changes = doc.rollback_transaction().unwrap();
+    assert_eq!(changes.data.len(), 2);
+    assert_eq!(doc.entities(false).count(), 1);
+    assert_eq!(
+        doc.get_entity(vec![START_NAME]).unwrap().properties().len(),
+        1
+    );
+    assert_eq!(doc.get_property::<i32>(vec![START_NAME], COLOR), Some(1));
+
+    // nothing of the discarded transaction is committed, and its names are reused
+    doc.create_entity().add(COLOR, 4);
+    assert!(doc.commit_transaction().is_ok());
+    assert_eq!(doc.history_size(), (2, 2));
+    assert_eq!(
+        doc.get_property::<i32>(vec![START_NAME + 1], COLOR),
+        Some(4)
+    );
+    assert!(doc.rollback_transaction().unwrap().data.is_empty());
+}
+
+#[test]
+fn savepoints() {
+    let mut doc = Document::new(1);
+    doc.create_entity().add(COLOR, 1);
+    assert!(doc.commit_transaction().is_ok());
+
+    // pick point
+    doc.update_entity(vec![START_NAME]).add(COLOR, 2);
+    assert!(doc.apply_transaction().is_ok());
+    let picked = doc.savepoint();
+
+    // preview
+    doc.update_entity(vec![START_NAME]).add(COLOR, 3);
+    doc.create_entity().add(COLOR, 4);
+    assert!(doc.apply_transaction().is_ok());
+    assert_eq!(doc.entities(false).count(), 2);
+
+    let changes = doc.rollback_to(&picked).unwrap();
+    assert_eq!(changes.data.len(), 2);
+    assert_eq!(doc.entities(false).count(), 1);
+    assert_eq!(doc.get_property::<i32>(vec![START_NAME], COLOR), Some(2));
+
+    // confirm
+    doc.create_entity().add(COLOR, 5);
+    assert!(doc.commit_transaction().is_ok());
+    assert_eq!(doc.history_size(), (2, 2));
+    assert_eq!(
+        doc.get_property::<i32>(vec![START_NAME + 1], COLOR),
+        Some(5)
+    );
+
+    assert!(matches!(
+        doc.rollback_to(&picked),
+        Err(d3s::Error::InvalidSavepoint)
+    ));
+    assert!(doc.undo(-1).is_ok());
+    assert_eq!(doc.get_property::<i32>(vec![START_NAME], COLOR), Some(1));
+}
+
+#[test]
+fn savepoint_after_undo() {
+    let mut doc = Document::new(1);
+    doc.create_entity().add(COLOR, 1);
+    assert!(doc.commit_transaction().is_ok());
+    doc.create_entity().add(COLOR, 3);
+    assert!(doc.commit_transaction().is_ok());
+
+    doc.update_entity(vec![START_NAME]).add(COLOR, 2);
+    assert!(doc.apply_transaction().is_ok());
+    let picked = doc.savepoint();
+
+    // the undo reverts the applied changes, the savepoint doesn't match the content any more
+    assert!(doc.undo(-1).is_ok());
+    assert!(matches!(
+        doc.rollback_to(&picked),
+        Err(d3s::Error::InvalidSavepoint)
+    ));
+    assert!(doc.commit_transaction().is_ok());
+    let (applied, _) = doc.history_size();
+    assert_eq!(doc.get_property::<i32>(vec![START_NAME], COLOR), Some(2));
+    assert_eq!(
+        doc.view_at(applied)
+            .unwrap()
+            .get_property::<i32>(vec![START_NAME], COLOR),
+        Some(2)
+    );
+}
+
+#[test]
+fn unchanged_values() {
+    let mut types = d3s::transaction::TypeRegistry::new();
+    types
+        .register_eq::<i32>(COLOR)
+        .register_partial_eq::<f64>(WIDTH);
+    let mut doc = Document::with_types(1, std::rc::Rc::new(types));
+    doc.create_entity()
+        .add(COLOR, 1)
+        .add(WIDTH, 0.5)
+        .add(TITLE, "Door");
+    assert!(doc.commit_transaction().is_ok());
+
+    // the values equal to the current ones are skipped, the unregistered keys are always updated
+    doc.update_entity(vec![START_NAME])
+        .add(COLOR, 1)
+        .add(WIDTH, 0.5);
+    assert!(doc.commit_transaction().unwrap().data.is_empty());
+    doc.update_entity(vec![START_NAME]).add(TITLE, "Door");
+    assert_eq!(doc.commit_transaction().unwrap().data.len(), 1);
+    doc.update_entity(vec![START_NAME]).add(COLOR, 2);
+    assert_eq!(doc.commit_transaction().unwrap().data.len(), 1);
+
+    let value = |key, value: i32| d3s::property::Value2 {
+        key,
+        value: Box::new(value),
+    };
+    let types = doc.types();
+    assert!(types.eq(&value(COLOR, 3), &value(COLOR, 3)));
+    assert!(!types.eq(&value(COLOR, 3), &value(COLOR, 4)));
+    assert!(!types.eq(&value(COLOR, 3), &value(TITLE, 3)));
+    assert_eq!(types.hash(&value(COLOR, 3)), types.hash(&value(COLOR, 3)));
+    assert!(types.hash(&value(TITLE, 3)).is_none());
+    assert_eq!(
+        types.cmp(&value(COLOR, 3), &value(COLOR, 4)),
+        Some(std::cmp::Ordering::Less)
+    );
+
+    // switching to a document with the equal values changes nothing
+    let mut doc = Document::with_types(11, types.clone());
+    doc.create_entity().add(COLOR, 7);
+    assert!(doc.commit_transaction().is_ok());
+    assert!(doc.switch(12).is_ok());
+    doc.create_entity().add(COLOR, 7);
+    assert!(doc.commit_transaction().is_ok());
+    assert!(doc.switch(11).unwrap().data.is_empty());
+}
+
+#[test]
+fn debug_format() {
+    let mut types = d3s::transaction::TypeRegistry::new();
+    types
+        .register_fmt::<&str>(TITLE)
+        .name_key(TITLE, "title")
+        .name_key(WIDTH, "width");
+    let mut doc = Document::with_types(1, std::rc::Rc::new(types));
+    doc.create_entity().add(TITLE, "Door").add(WIDTH, 900.0);
+    doc.create_entity();
+    assert!(doc.commit_transaction().is_ok());
+
+    let door = doc.get_entity(vec![START_NAME]).unwrap();
+    assert_eq!(format!("{door:?}"), "#0 { 102: \"Door\", 103: 900.0 }\n");
+    assert_eq!(
+        format!("{:?}", doc.types().debug(door)),
+        "#0 { title: \"Door\", width: 900.0 }\n"
+    );
+
+    assert!(doc.switch(2).is_ok());
+    doc.create_entity().add(INS_DOC, 1 as DocId).add(COLOR, 5);
+    assert!(doc.commit_transaction().is_ok());
+    assert_eq!(
+        format!("{doc:?}"),
+        "Document 2:\n  #0 { insDoc: 1, 101: 5 }\n    #0 { title: \"Door\", width: 900.0 }\n    #1 {}\n"
+    );
+}
+
+#[test]
+fn schema_validation() {
+    let mut types = d3s::transaction::TypeRegistry::new();
+    types
+        .register::<i32>(COLOR)
+        .register::<f64>(WIDTH)
+        .require_keys(TITLE, &[WIDTH]);
+    let mut doc = Document::with_types(1, std::rc::Rc::new(types));
+
+    doc.create_entity().add(COLOR, 1);
+    doc.create_entity().add(COLOR, "red");
+    match doc.commit_transaction() {
+        Err(d3s::Error::InvalidProperty(name, key)) => {
+            assert_eq!(name, vec![START_NAME + 1]);
+            assert_eq!(key, COLOR);
+        }
+        _ => panic!("a value of wrong type must be rejected"),
+    }
+    // nothing is applied
+    assert_eq!(doc.entities(false).count(), 0);
+    assert!(doc.rollback_transaction().is_ok());
+
+    // the keys without the type defined accept anything
+    doc.create_entity().add(TITLE, "Door").add(TITLE + 10, 1);
+    match doc.commit_transaction() {
+        Err(d3s::Error::MissingProperty(name, key)) => {
+            assert_eq!(name, vec![START_NAME]);
+            assert_eq!(key, WIDTH);
+        }
+        _ => panic!("the required key must be present"),
+    }
+    // the failed commit changes nothing
+    assert_eq!(doc.entities(false).count(), 0);
+
+    // the incomplete entity may be completed before commit
+    doc.update_entity(vec![START_NAME]).add(WIDTH, 900.0);
+    assert!(doc.commit_transaction().is_ok());
+
+    // the deletion made by the failed commit is made again by the next one
+    doc.delete_entity(vec![START_NAME]);
+    doc.create_entity().add(TITLE, "Window");
+    assert!(matches!(
+        doc.commit_transaction(),
+        Err(d3s::Error::MissingProperty(_, WIDTH))
+    ));
+    assert_eq!(doc.entities(false).count(), 1);
+    doc.update_entity(vec![START_NAME + 1]).add(WIDTH, 600.0);
+    assert!(doc.commit_transaction().is_ok());
+    assert!(doc.get_entity(vec![START_NAME]).is_none());
+    assert!(doc.undo(-1).is_ok());
+    doc.update_entity(vec![START_NAME]).delete(WIDTH);
+    assert!(matches!(
+        doc.commit_transaction(),
+        Err(d3s::Error::MissingProperty(_, WIDTH))
+    ));
+    assert!(doc.rollback_transaction().is_ok());
+    doc.update_entity(vec![START_NAME])
+        .delete(WIDTH)
+        .delete(TITLE);
+    assert!(doc.commit_transaction().is_ok());
+}
+
+#[test]
+fn links() {
+    use d3s::entity::{CHG_DELETED, CHG_DEL_PROP};
+    use d3s::property::{Link, LinkPolicy};
+
+    const HOST: KT = 201;
+    const DIMENSION: KT = 202;
+    const NOTE: KT = 203;
+    let link = |name: u32| Link { target: vec![name] };
+
+    let mut types = d3s::transaction::TypeRegistry::new();
+    types
+        .register_link(HOST, LinkPolicy::Cascade)
+        .register_link(DIMENSION, LinkPolicy::Nullify)
+        .register_link(NOTE, LinkPolicy::Reject);
+    let mut doc = Document::with_types(1, std::rc::Rc::new(types));
+    let wall = START_NAME;
+    doc.create_entity().add(TITLE, "Wall");
+    doc.create_entity().add(HOST, link(wall));
+    doc.create_entity().add(DIMENSION, link(wall));
+    doc.create_entity().add(NOTE, link(wall + 1));
+    assert!(doc.commit_transaction().is_ok());
+
+    let mut backlinks = doc.backlinks(&vec![wall]).to_vec();
+    backlinks.sort();
+    assert_eq!(
+        backlinks,
+        [(vec![wall + 1], HOST), (vec![wall + 2], DIMENSION)]
+    );
+
+    // the door hosted by the wall is deleted with it, but the note rejects deletion of the door
+    doc.delete_entity(vec![wall]);
+    match doc.commit_transaction() {
+        Err(d3s::Error::EntityLinked(target, source)) => {
+            assert_eq!(target, vec![wall + 1]);
+            assert_eq!(source, vec![wall + 3]);
+        }
+        _ => panic!("the linked entity must not be deleted"),
+    }
+    assert!(doc.rollback_transaction().is_ok());
+
+    doc.delete_entity(vec![wall + 3]);
+    doc.delete_entity(vec![wall]);
+    let changes = doc.commit_transaction().unwrap();
+    assert_eq!(changes.data[&vec![wall]], CHG_DELETED);
+    assert_eq!(changes.data[&vec![wall + 1]], CHG_DELETED);
+    assert_eq!(changes.data[&vec![wall + 2]], CHG_DEL_PROP);
+    assert_eq!(doc.entities(false).count(), 1);
+    assert!(doc.backlinks(&vec![wall]).is_empty());
+
+    assert!(doc.undo(-1).is_ok());
+    assert_eq!(doc.backlinks(&vec![wall]).len(), 2);
+    assert_eq!(doc.backlinks(&vec![wall + 1]).len(), 1);
+
+    doc.create_entity().add(HOST, link(wall + 10));
+    assert!(matches!(
+        doc.commit_transaction(),
+        Err(d3s::Error::BrokenLink(_, HOST))
+    ));
+}
+
+#[test]
+fn observers() {
+    use d3s::entity::{Entity, EntityUser, Name, Observed, CHG_DELETED, CHG_UPD_PROP};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Default)]
+    struct Recorder {
+        log: Vec<(Name, bool, u32)>,
+    }
+    impl EntityUser for Recorder {
+        fn on_change(&mut self, name: &Name, entity: Option<&Entity>, flags: u32) {
+            self.log.push((name.clone(), entity.is_some(), flags));
+        }
+    }
+
+    let all = Rc::new(RefCell::new(Recorder::default()));
+    let second = Rc::new(RefCell::new(Recorder::default()));
+    let titles = Rc::new(RefCell::new(Recorder::default()));
+    let mut doc = Document::new(1);
+    doc.observe(Observed::All, &(all.clone() as Rc<RefCell<dyn EntityUser>>));
+    let second_user: Rc<RefCell<dyn EntityUser>> = second.clone();
+    doc.observe(Observed::Entity(vec![START_NAME + 1]), &second_user);
+    let titles_user: Rc<RefCell<dyn EntityUser>> = titles.clone();
+    doc.observe(Observed::Key(TITLE), &titles_user);
+
+    doc.create_entity().add(COLOR, 1);
+    doc.create_entity().add(TITLE, "Door");
+    assert!(doc.commit_transaction().is_ok());
+    doc.update_entity(vec![START_NAME]).add(COLOR, 2);
+    assert!(doc.commit_transaction().is_ok());
+    assert_eq!(all.borrow().log.len(), 3);
+    assert_eq!(second.borrow().log.len(), 1);
+    assert_eq!(titles.borrow().log.len(), 1);
+    assert_eq!(all.borrow().log[2], (vec![START_NAME], true, CHG_UPD_PROP));
+
+    // undo of the creation reports the deleted entities
+    assert!(doc.undo(-2).is_ok());
+    assert_eq!(all.borrow().log.len(), 5);
+    let (name, exists, flags) = second.borrow().log[1].clone();
+    assert_eq!(name, vec![START_NAME + 1]);
+    assert!(!exists && flags & CHG_DELETED != 0);
+    assert_eq!(titles.borrow().log.len(), 2);
+
+    // redo, the observers dropped or removed aren't notified
+    drop(second_user);
+    drop(second);
+    doc.unobserve(&titles_user);
+    assert!(doc.undo(2).is_ok());
+    assert_eq!(all.borrow().log.len(), 7);
+    assert_eq!(titles.borrow().log.len(), 2);
+
+    // the failed commit isn't reported
+    let link = d3s::property::Link {
+        target: vec![START_NAME + 9],
+    };
+    doc.create_entity().add(201, link);
+    assert!(matches!(
+        doc.commit_transaction(),
+        Err(d3s::Error::BrokenLink(_, 201))
+    ));
+    assert_eq!(all.borrow().log.len(), 7);
+}
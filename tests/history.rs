@@ -0,0 +1,518 @@
+use std::rc::Rc;
+
+use d3s::entity::{
+    Document, CHG_ADD_PROP, CHG_CREATED, CHG_DELETED, CHG_DEL_PROP, CHG_UPD_PROP, START_NAME,
+};
+use d3s::property::{DocId, INS_DOC, KT};
+use d3s::transaction::TypeRegistry;
+
+pub const COLOR: KT = 101; //"color";
+pub const TITLE: KT = 102; //"title";
+
+/// Document with one entity, which color was changed `count` times: 0, 1, 2...
+fn colored_document(count: i32) -> Document {
+    let mut types = TypeRegistry::new();
+    types.register::<i32>(COLOR);
+
+    let mut doc = Document::with_types(1, Rc::new(types));
+    doc.create_entity().add(COLOR, 0);
+    assert!(doc.commit_transaction().is_ok());
+    for color in 1..count {
+        doc.update_entity(vec![START_NAME]).add(COLOR, color);
+        assert!(doc.commit_transaction().is_ok());
+    }
+    doc
+}
+
+#[test]
+fn snapshots() {
+    let mut doc = colored_document(1);
+    doc.set_snapshot_interval(2);
+    for color in 1..7 {
+        doc.update_entity(vec![START_NAME]).add(COLOR, color);
+        assert!(doc.commit_transaction().is_ok());
+    }
+
+    assert!(doc.undo(-3).is_ok());
+    assert_eq!(doc.get_property::<i32>(vec![START_NAME], COLOR), Some(3));
+    assert!(doc.undo(-3).is_ok());
+    assert_eq!(doc.get_property::<i32>(vec![START_NAME], COLOR), Some(0));
+    assert!(doc.undo(5).is_ok());
+    assert_eq!(doc.get_property::<i32>(vec![START_NAME], COLOR), Some(5));
+
+    // snapshots made after the current position are dropped with the redo history
+    doc.update_entity(vec![START_NAME]).add(COLOR, 55);
+    assert!(doc.commit_transaction().is_ok());
+    assert!(doc.undo(-1).is_ok());
+    assert!(doc.undo(1).is_ok());
+    assert_eq!(doc.get_property::<i32>(vec![START_NAME], COLOR), Some(55));
+}
+
+#[test]
+fn compaction() {
+    let mut doc = colored_document(10);
+    assert!(doc.undo(-2).is_ok());
+    assert!(doc.compact(3).is_ok());
+    assert_eq!(doc.history_size(), (5, 3));
+    assert_eq!(doc.get_property::<i32>(vec![START_NAME], COLOR), Some(7));
+
+    assert!(doc.undo(-4).is_err());
+    assert!(doc.undo(-3).is_ok());
+    assert_eq!(doc.get_property::<i32>(vec![START_NAME], COLOR), Some(4));
+    assert!(doc.undo(5).is_ok());
+    assert_eq!(doc.get_property::<i32>(vec![START_NAME], COLOR), Some(9));
+
+    // the compacted history is saved with its snapshot
+    let mut file = vec![];
+    assert!(doc.save_to(&mut file).is_ok());
+    let mut opened = Document::open_from(&mut file.as_slice(), doc.types().clone()).unwrap();
+    assert_eq!(opened.history_size(), (5, 5));
+    assert_eq!(opened.get_property::<i32>(vec![START_NAME], COLOR), Some(9));
+    assert!(opened.undo(-5).is_ok());
+    assert_eq!(opened.get_property::<i32>(vec![START_NAME], COLOR), Some(4));
+
+    // new entities don't reuse the names of the compacted ones
+    opened.create_entity();
+    assert!(opened.commit_transaction().is_ok());
+    assert_eq!(opened.entities(false).count(), 2);
+}
+
+#[test]
+fn insert_compacted_document() {
+    let mut doc = colored_document(5);
+    assert!(doc.compact(0).is_ok());
+    assert_eq!(doc.history_size(), (0, 0));
+
+    assert!(doc.switch(2).is_ok());
+    doc.create_entity().add(INS_DOC, 1 as DocId);
+    assert!(doc.commit_transaction().is_ok());
+    assert_eq!(
+        doc.get_property::<i32>(vec![START_NAME, START_NAME], COLOR),
+        Some(4)
+    );
+}
+
+#[test]
+fn undo_only_changed() {
+    let mut doc = Document::new(1);
+    doc.create_entity().add(COLOR, 1);
+    doc.create_entity().add(COLOR, 2);
+    doc.create_entity().add(COLOR, 3);
+    assert!(doc.commit_transaction().is_ok());
+
+    doc.update_entity(vec![START_NAME + 1])
+        .add(COLOR, 22)
+        .add(TITLE, "second")
+        .delete(COLOR);
+    assert!(doc.commit_transaction().is_ok());
+
+    let changes = doc.undo(-1).unwrap();
+    assert_eq!(changes.data.len(), 1);
+    assert_eq!(
+        changes.data[&vec![START_NAME + 1]],
+        CHG_ADD_PROP | CHG_UPD_PROP | CHG_DEL_PROP
+    );
+    assert_eq!(
+        doc.get_property::<i32>(vec![START_NAME + 1], COLOR),
+        Some(2)
+    );
+    assert!(doc
+        .get_property::<&str>(vec![START_NAME + 1], TITLE)
+        .is_none());
+
+    let changes = doc.undo(1).unwrap();
+    assert_eq!(changes.data.len(), 1);
+    assert!(doc
+        .get_property::<i32>(vec![START_NAME + 1], COLOR)
+        .is_none());
+    assert_eq!(
+        doc.get_property::<&str>(vec![START_NAME + 1], TITLE),
+        Some("second")
+    );
+}
+
+#[test]
+fn undo_delete_keeps_order() {
+    let mut doc = Document::new(1);
+    for color in 0..4 {
+        doc.create_entity().add(COLOR, color).add(TITLE, "entity");
+    }
+    assert!(doc.commit_transaction().is_ok());
+    let order = |doc: &Document| -> Vec<i32> {
+        doc.entities(false)
+            .map(|e| e.get_property::<i32>(COLOR).unwrap())
+            .collect()
+    };
+    let before = order(&doc);
+
+    doc.delete_entity(vec![START_NAME + 1]);
+    assert!(doc.commit_transaction().is_ok());
+    doc.update_entity(vec![START_NAME + 2]).delete(COLOR);
+    assert!(doc.commit_transaction().is_ok());
+
+    let changes = doc.undo(-2).unwrap();
+    assert_eq!(changes.data[&vec![START_NAME + 1]], CHG_CREATED);
+    assert_eq!(order(&doc), before);
+    assert_eq!(
+        doc.get_entity(vec![START_NAME + 2])
+            .unwrap()
+            .properties()
+            .iter()
+            .map(|p| p.key)
+            .collect::<Vec<KT>>(),
+        vec![COLOR, TITLE]
+    );
+}
+
+#[test]
+fn undo_insert_document() {
+    let mut doc = Document::new(2);
+    doc.create_entity().add(COLOR, 2);
+    assert!(doc.commit_transaction().is_ok());
+
+    assert!(doc.switch(1).is_ok());
+    doc.create_entity().add(INS_DOC, 2 as DocId);
+    let changes = doc.commit_transaction().unwrap();
+    assert!(changes.data[&vec![START_NAME, START_NAME]] & CHG_CREATED != 0);
+
+    let changes = doc.undo(-1).unwrap();
+    assert!(changes.data[&vec![START_NAME]] & CHG_DELETED != 0);
+    assert_eq!(changes.data[&vec![START_NAME, START_NAME]], CHG_DELETED);
+    assert_eq!(doc.entities(true).count(), 0);
+
+    assert!(doc.undo(1).is_ok());
+    assert_eq!(doc.entities(true).count(), 2);
+}
+
+#[test]
+fn switch_changes() {
+    let mut doc = Document::new(1);
+    doc.create_entity().add(COLOR, 1);
+    doc.create_entity().add(COLOR, 1);
+    assert!(doc.commit_transaction().is_ok());
+
+    let changes = doc.switch(2).unwrap();
+    assert_eq!(changes.data.len(), 2);
+    assert!(changes.data.values().all(|&flags| flags == CHG_DELETED));
+
+    doc.create_entity().add(COLOR, 2).add(TITLE, "second");
+    assert!(doc.commit_transaction().is_ok());
+
+    let changes = doc.switch(1).unwrap();
+    assert_eq!(changes.data[&vec![START_NAME]], CHG_UPD_PROP | CHG_DEL_PROP);
+    assert_eq!(changes.data[&vec![START_NAME + 1]], CHG_CREATED);
+
+    // nothing changes when switching to the same document
+    assert!(doc.switch(1).unwrap().data.is_empty());
+}
+
+#[test]
+fn transaction_info() {
+    let mut doc = colored_document(1);
+    doc.update_entity(vec![START_NAME]).add(COLOR, 1);
+    doc.transaction_info()
+        .set_label("Paint")
+        .set_author("alice")
+        .add_tag("tool", "brush");
+    assert!(doc.commit_transaction().is_ok());
+
+    // the label is dropped with the rolled back transaction
+    doc.transaction_info().set_label("Forgotten");
+    assert!(doc.rollback_transaction().is_ok());
+    doc.create_entity().add(COLOR, 2);
+    assert!(doc.commit_transaction().is_ok());
+    assert!(doc.undo(-1).is_ok());
+
+    let mut saved = vec![];
+    assert!(doc.save_to(&mut saved).is_ok());
+    let doc = Document::open_from(&mut saved.as_slice(), doc.types().clone()).unwrap();
+
+    let history: Vec<_> = doc.history().collect();
+    assert_eq!(history.len(), 3);
+    assert_eq!(doc.history_size(), (3, 2));
+    assert_eq!(history[0].label(), None);
+    assert_eq!(history[1].label(), Some("Paint"));
+    assert_eq!(history[1].author(), Some("alice"));
+    assert_eq!(
+        history[1].tags().get("tool").map(String::as_str),
+        Some("brush")
+    );
+    assert_eq!(history[2].label(), None);
+    assert!(history.iter().all(|info| info.timestamp().is_some()));
+    assert!(history[1].timestamp() <= history[2].timestamp());
+}
+
+#[test]
+fn undo_tree() {
+    // 0 -> 1 -> 2
+    //        \-> 11 -> 12
+    let mut doc = colored_document(3);
+    let two = doc.current_node().unwrap();
+    assert!(doc.undo(-1).is_ok());
+    let one = doc.current_node().unwrap();
+    let first = doc.history_node(one).unwrap().parent.unwrap();
+    for color in [11, 12] {
+        doc.update_entity(vec![START_NAME]).add(COLOR, color);
+        assert!(doc.commit_transaction().is_ok());
+    }
+    let twelve = doc.current_node().unwrap();
+    assert_eq!(doc.history_size(), (4, 4));
+    assert!(doc.undo(1).is_err());
+
+    let branches = doc.branches();
+    assert_eq!(branches, vec![two, twelve]);
+    assert_eq!(doc.history_node(two).unwrap().parent, Some(one));
+    assert_eq!(doc.history_node(first).unwrap().parent, None);
+
+    let changes = doc.jump(two).unwrap();
+    assert_eq!(changes.data[&vec![START_NAME]], CHG_UPD_PROP);
+    assert_eq!(doc.get_property::<i32>(vec![START_NAME], COLOR), Some(2));
+    assert_eq!(doc.history_size(), (3, 3));
+    assert_eq!(doc.current_node(), Some(two));
+
+    // the tree survives saving
+    let mut saved = vec![];
+    assert!(doc.save_to(&mut saved).is_ok());
+    let mut doc = Document::open_from(&mut saved.as_slice(), doc.types().clone()).unwrap();
+    assert_eq!(doc.branches(), branches);
+
+    assert!(doc.jump(twelve).is_ok());
+    assert_eq!(doc.get_property::<i32>(vec![START_NAME], COLOR), Some(12));
+    assert!(doc.jump(one).is_ok());
+    assert_eq!(doc.get_property::<i32>(vec![START_NAME], COLOR), Some(1));
+    assert_eq!(doc.history_size(), (4, 2));
+
+    assert!(matches!(doc.prune(first), Err(d3s::Error::NodeApplied(_))));
+    assert!(doc.prune(two).is_ok());
+    assert_eq!(doc.branches(), vec![twelve]);
+    assert!(matches!(doc.jump(two), Err(d3s::Error::NodeNotFound(_))));
+
+    // the next commit starts a new branch, new identifiers aren't taken by the pruned nodes
+    doc.update_entity(vec![START_NAME]).add(COLOR, 21);
+    assert!(doc.commit_transaction().is_ok());
+    assert!(doc.current_node().unwrap() > twelve);
+    assert_eq!(doc.branches().len(), 2);
+
+    // the branches departing from the compacted transactions are dropped
+    let latest = doc.current_node().unwrap();
+    assert!(doc.compact(1).is_ok());
+    assert_eq!(doc.branches(), vec![twelve, latest]);
+    assert!(doc.jump(twelve).is_ok());
+    assert_eq!(doc.get_property::<i32>(vec![START_NAME], COLOR), Some(12));
+    assert!(doc.jump(latest).is_ok());
+    assert!(doc.compact(0).is_ok());
+    assert!(doc.branches().is_empty());
+    assert_eq!(doc.get_property::<i32>(vec![START_NAME], COLOR), Some(21));
+}
+
+#[test]
+fn view_at() {
+    let mut doc = colored_document(3);
+    doc.create_entity().add(TITLE, "Door");
+    assert!(doc.commit_transaction().is_ok());
+    doc.update_entity(vec![START_NAME]).add(COLOR, 33);
+
+    let before = doc.view_at(1).unwrap();
+    assert_eq!(before.position(), 1);
+    assert_eq!(before.entities(false).count(), 1);
+    assert_eq!(before.get_property::<i32>(vec![START_NAME], COLOR), Some(0));
+    assert!(before.get_entity(vec![START_NAME + 1]).is_none());
+
+    let after = doc.view_at(4).unwrap();
+    assert_eq!(after.entities(false).count(), 2);
+    assert_eq!(after.get_property::<i32>(vec![START_NAME], COLOR), Some(2));
+    assert_eq!(
+        after.get_property::<&str>(vec![START_NAME + 1], TITLE),
+        Some("Door")
+    );
+
+    assert!(doc.view_at(0).unwrap().entities(true).next().is_none());
+    assert!(matches!(doc.view_at(5), Err(d3s::Error::HistoryOverflow)));
+
+    // the document itself is kept as it is
+    assert_eq!(doc.history_size(), (4, 4));
+    assert!(doc.commit_transaction().is_ok());
+    assert_eq!(doc.get_property::<i32>(vec![START_NAME], COLOR), Some(33));
+}
+
+#[test]
+fn blame() {
+    let mut doc = colored_document(1);
+    doc.create_entity().add(TITLE, "Wall");
+    assert!(doc.commit_transaction().is_ok());
+    doc.update_entity(vec![START_NAME]).add(TITLE, "Door");
+    doc.transaction_info().set_author("bob");
+    assert!(doc.commit_transaction().is_ok());
+    doc.update_entity(vec![START_NAME]).add(COLOR, 5);
+    assert!(doc.commit_transaction().is_ok());
+    doc.delete_entity(vec![START_NAME]);
+    assert!(doc.commit_transaction().is_ok());
+
+    let revisions = doc.blame(vec![START_NAME], None).unwrap();
+    let flags: Vec<u32> = revisions.iter().map(|r| r.flags).collect();
+    assert_eq!(
+        flags,
+        [
+            CHG_CREATED | CHG_ADD_PROP,
+            CHG_ADD_PROP,
+            CHG_UPD_PROP,
+            CHG_DELETED
+        ]
+    );
+    assert_eq!(revisions[1].pos, 3);
+    assert_eq!(revisions[1].info.author(), Some("bob"));
+    assert_eq!(revisions[3].props.len(), 2);
+    assert!(revisions[3].props.iter().all(|p| p.new.is_none()));
+
+    let colors = doc.blame(vec![START_NAME], Some(COLOR)).unwrap();
+    assert_eq!(colors.len(), 3);
+    let change = &colors[1].props[0];
+    assert_eq!(change.key, COLOR);
+    assert_eq!(
+        change.old.as_ref().unwrap().value.downcast_ref::<i32>(),
+        Some(&0)
+    );
+    assert_eq!(
+        change.new.as_ref().unwrap().value.downcast_ref::<i32>(),
+        Some(&5)
+    );
+
+    // the other entity isn't touched by the transactions after its creation
+    assert_eq!(doc.blame(vec![START_NAME + 1], None).unwrap().len(), 1);
+    assert!(doc.blame(vec![START_NAME + 2], None).unwrap().is_empty());
+}
+
+#[test]
+fn diff() {
+    let mut doc = colored_document(1);
+    doc.create_entity().add(TITLE, "Wall");
+    doc.create_entity().add(TITLE, "Door");
+    assert!(doc.commit_transaction().is_ok());
+    doc.update_entity(vec![START_NAME])
+        .add(COLOR, 7)
+        .add(TITLE, "Floor");
+    doc.update_entity(vec![START_NAME + 1]).delete(TITLE);
+    doc.delete_entity(vec![START_NAME + 2]);
+    doc.create_entity().add(COLOR, 8);
+    assert!(doc.commit_transaction().is_ok());
+
+    let diff = doc.diff_history(2, 3).unwrap();
+    let flags: Vec<(Vec<u32>, u32)> = diff
+        .entities
+        .iter()
+        .map(|e| (e.name.clone(), e.flags))
+        .collect();
+    assert_eq!(
+        flags,
+        [
+            (vec![START_NAME], CHG_UPD_PROP | CHG_ADD_PROP),
+            (vec![START_NAME + 1], CHG_DEL_PROP),
+            (vec![START_NAME + 2], CHG_DELETED),
+            (vec![START_NAME + 3], CHG_CREATED),
+        ]
+    );
+    assert!(doc.diff_history(3, 3).unwrap().is_empty());
+
+    // the diff applied to the earlier state gives the later one
+    let reverse = doc.diff_history(3, 2).unwrap();
+    doc.apply_diff(&reverse);
+    assert!(doc.commit_transaction().is_ok());
+    assert!(doc.diff_history(2, 4).unwrap().is_empty());
+    assert_eq!(doc.get_property::<i32>(vec![START_NAME], COLOR), Some(0));
+    assert!(doc.get_entity(vec![START_NAME + 3]).is_none());
+
+    assert!(doc.undo(-1).is_ok());
+    let mut copy = Document::with_types(2, doc.types().clone());
+    copy.apply_diff(&copy.diff(&doc).unwrap());
+    assert!(copy.commit_transaction().is_ok());
+    assert!(copy.diff(&doc).unwrap().is_empty());
+    copy.create_entity();
+    assert!(copy.commit_transaction().is_ok());
+    assert!(copy.get_entity(vec![START_NAME + 4]).is_some());
+}
+
+#[test]
+fn diff_inserted_document() {
+    let mut types = TypeRegistry::new();
+    types.register::<i32>(COLOR);
+    let types = Rc::new(types);
+
+    let mut doc = Document::with_types(1, types.clone());
+    doc.create_entity().add(COLOR, 1);
+    doc.create_entity().add(COLOR, 2);
+    assert!(doc.commit_transaction().is_ok());
+
+    let id: DocId = 2;
+    assert!(doc.switch(id).is_ok());
+    doc.create_entity().add(INS_DOC, 1 as DocId);
+    assert!(doc.commit_transaction().is_ok());
+    doc.update_entity(vec![START_NAME, START_NAME + 1])
+        .add(COLOR, 3);
+    doc.delete_entity(vec![START_NAME, START_NAME]);
+    assert!(doc.commit_transaction().is_ok());
+
+    // reading the history doesn't need to change the document
+    let doc: &Document = &doc;
+    assert_eq!(
+        doc.blame(vec![START_NAME, START_NAME + 1], None)
+            .unwrap()
+            .len(),
+        2
+    );
+    let diff = doc.diff_history(1, 2).unwrap();
+    let flags: Vec<(Vec<u32>, u32)> = diff
+        .entities
+        .iter()
+        .map(|e| (e.name.clone(), e.flags))
+        .collect();
+    assert_eq!(
+        flags,
+        [
+            (vec![START_NAME, START_NAME], CHG_DELETED),
+            (vec![START_NAME, START_NAME + 1], CHG_UPD_PROP),
+        ]
+    );
+}
+
+#[test]
+fn diff_created_inserted_document() {
+    let mut types = TypeRegistry::new();
+    types.register::<i32>(COLOR);
+    let mut doc = Document::with_types(1, Rc::new(types));
+    doc.create_entity().add(COLOR, 1);
+    doc.create_entity().add(COLOR, 2);
+    assert!(doc.commit_transaction().is_ok());
+
+    assert!(doc.switch(2).is_ok());
+    doc.create_entity().add(INS_DOC, 1 as DocId);
+    assert!(doc.commit_transaction().is_ok());
+    doc.update_entity(vec![START_NAME, START_NAME + 1])
+        .add(COLOR, 3);
+    doc.delete_entity(vec![START_NAME, START_NAME]);
+    assert!(doc.commit_transaction().is_ok());
+
+    // the local overrides of the created inserted document are the part of the diff
+    let diff = doc.diff_history(0, 2).unwrap();
+    let flags: Vec<(Vec<u32>, u32)> = diff
+        .entities
+        .iter()
+        .map(|e| (e.name.clone(), e.flags))
+        .collect();
+    assert_eq!(
+        flags,
+        [
+            (vec![START_NAME], CHG_CREATED),
+            (vec![START_NAME, START_NAME], CHG_DELETED),
+            (vec![START_NAME, START_NAME + 1], CHG_UPD_PROP),
+        ]
+    );
+
+    assert!(doc.undo(-2).is_ok());
+    doc.apply_diff(&diff);
+    assert!(doc.commit_transaction().is_ok());
+    assert!(doc.get_entity(vec![START_NAME, START_NAME]).is_none());
+    assert_eq!(
+        doc.get_property::<i32>(vec![START_NAME, START_NAME + 1], COLOR),
+        Some(3)
+    );
+}
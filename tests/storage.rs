@@ -0,0 +1,431 @@
+use std::rc::Rc;
+
+use d3s::entity::{Document, START_NAME};
+use d3s::property::{DocId, INS_DOC, KT};
+use d3s::transaction::{EntityChanges, PropChange, TypeRegistry};
+
+pub const COLOR: KT = 101; //"color";
+pub const TITLE: KT = 102; //"title";
+pub const WIDTH: KT = 103; //"width";
+
+fn types() -> TypeRegistry {
+    let mut types = TypeRegistry::new();
+    types
+        .register::<i32>(COLOR)
+        .register::<String>(TITLE)
+        .register::<f64>(WIDTH);
+    types
+}
+
+#[test]
+fn entity_changes_roundtrip() {
+    let types = types();
+    let mut changes = EntityChanges {
+        ename: vec![START_NAME + 1, START_NAME],
+        props: vec![],
+    };
+    changes
+        .add(COLOR, 7)
+        .add(TITLE, String::from("Door"))
+        .add(WIDTH, 900.0)
+        .add(INS_DOC, 5 as DocId)
+        .delete(COLOR);
+
+    let mut buf = vec![];
+    assert!(changes.save(&types, &mut buf).is_ok());
+    let loaded = EntityChanges::load(&types, &mut buf.as_slice()).unwrap();
+
+    assert_eq!(loaded.ename, changes.ename);
+    assert_eq!(loaded.props.len(), 5);
+    let values: Vec<Rc<d3s::property::Value2>> = loaded
+        .props
+        .iter()
+        .filter_map(|p| match p {
+            PropChange::Update(v) => Some(v.clone()),
+            PropChange::Delete(_) => None,
+        })
+        .collect();
+    assert_eq!(values[0].value.downcast_ref::<i32>(), Some(&7));
+    assert_eq!(values[1].value.downcast_ref::<String>().unwrap(), "Door");
+    assert_eq!(values[2].value.downcast_ref::<f64>(), Some(&900.0));
+    assert_eq!(values[3].value.downcast_ref::<DocId>(), Some(&5));
+    assert!(matches!(loaded.props[4], PropChange::Delete(COLOR)));
+}
+
+#[test]
+fn unregistered_key_is_an_error() {
+    let mut changes = EntityChanges {
+        ename: vec![START_NAME],
+        props: vec![],
+    };
+    changes.add(COLOR, 7);
+
+    let mut buf = vec![];
+    assert!(matches!(
+        changes.save(&TypeRegistry::new(), &mut buf),
+        Err(d3s::Error::UnknownKey(COLOR))
+    ));
+
+    buf.clear();
+    assert!(changes.save(&types(), &mut buf).is_ok());
+    assert!(matches!(
+        EntityChanges::load(&TypeRegistry::new(), &mut buf.as_slice()),
+        Err(d3s::Error::UnknownKey(COLOR))
+    ));
+}
+
+fn sample_document(types: Rc<TypeRegistry>) -> Document {
+    let mut doc = Document::with_types(7, types);
+    doc.create_entity()
+        .add(COLOR, 1)
+        .add(TITLE, String::from("Wall"));
+    assert!(doc.commit_transaction().is_ok());
+    doc.create_entity().add(WIDTH, 900.0);
+    assert!(doc.commit_transaction().is_ok());
+    doc.update_entity(vec![START_NAME]).add(COLOR, 2);
+    assert!(doc.commit_transaction().is_ok());
+    doc
+}
+
+#[test]
+fn document_roundtrip() {
+    let types = Rc::new(types());
+    let mut doc = sample_document(types.clone());
+    assert!(doc.undo(-1).is_ok());
+
+    let mut file = vec![];
+    assert!(doc.save_to(&mut file).is_ok());
+
+    let mut opened = Document::open_from(&mut file.as_slice(), types).unwrap();
+    assert_eq!(opened.history_size(), (3, 2));
+    assert_eq!(opened.entities(false).count(), 2);
+    assert_eq!(opened.get_property::<i32>(vec![START_NAME], COLOR), Some(1));
+    assert_eq!(
+        opened.get_property::<f64>(vec![START_NAME + 1], WIDTH),
+        Some(900.0)
+    );
+
+    // the undo history survives reopening
+    assert!(opened.undo(1).is_ok());
+    assert_eq!(opened.get_property::<i32>(vec![START_NAME], COLOR), Some(2));
+
+    // new entities continue the sequence of names
+    opened.create_entity();
+    assert!(opened.commit_transaction().is_ok());
+    assert!(opened.get_entity(vec![START_NAME + 2]).is_some());
+}
+
+#[test]
+fn damaged_document() {
+    let types = Rc::new(types());
+    let doc = sample_document(types.clone());
+    let mut file = vec![];
+    assert!(doc.save_to(&mut file).is_ok());
+
+    assert!(Document::open_from(&mut &file[1..], types.clone()).is_err());
+
+    let last = file.len() - 6;
+    file[last] ^= 0xff;
+    assert!(matches!(
+        Document::open_from(&mut file.as_slice(), types.clone()),
+        Err(d3s::Error::Corrupted(_))
+    ));
+
+    file.truncate(last);
+    assert!(Document::open_from(&mut file.as_slice(), types).is_err());
+}
+
+fn journal_path(test: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("d3s-{}-{test}.journal", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+#[test]
+fn journal_replay() {
+    let types = Rc::new(types());
+    let path = journal_path("replay");
+    {
+        let mut doc = Document::with_types(7, types.clone());
+        assert!(doc.open_journal(&path).is_ok());
+        doc.create_entity().add(COLOR, 1);
+        assert!(doc.commit_transaction().is_ok());
+        doc.update_entity(vec![START_NAME]).add(COLOR, 2);
+        assert!(doc.commit_transaction().is_ok());
+        assert!(doc.undo(-1).is_ok());
+        doc.create_entity().add(COLOR, 3);
+        assert!(doc.commit_transaction().is_ok());
+        // the active transaction is lost in a crash
+        doc.create_entity().add(COLOR, 4);
+    }
+
+    let mut doc = Document::with_types(7, types);
+    assert!(doc.open_journal(&path).is_ok());
+    assert_eq!(doc.history_size(), (2, 2));
+    assert_eq!(doc.entities(false).count(), 2);
+    assert_eq!(doc.get_property::<i32>(vec![START_NAME], COLOR), Some(1));
+    assert_eq!(
+        doc.get_property::<i32>(vec![START_NAME + 1], COLOR),
+        Some(3)
+    );
+
+    doc.create_entity();
+    assert!(doc.commit_transaction().is_ok());
+    assert!(doc.get_entity(vec![START_NAME + 2]).is_some());
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn journal_branches() {
+    let types = Rc::new(types());
+    let path = journal_path("branches");
+    let (first, second) = {
+        let mut doc = Document::with_types(7, types.clone());
+        assert!(doc.open_journal(&path).is_ok());
+        doc.create_entity().add(COLOR, 1);
+        assert!(doc.commit_transaction().is_ok());
+        let first = doc.current_node().unwrap();
+        assert!(doc.undo(-1).is_ok());
+        doc.create_entity().add(COLOR, 2);
+        assert!(doc.commit_transaction().is_ok());
+        let second = doc.current_node().unwrap();
+        doc.update_entity(vec![START_NAME]).add(COLOR, 3);
+        assert!(doc.commit_transaction().is_ok());
+        assert!(doc.jump(first).is_ok());
+        assert!(doc.prune(second).is_ok());
+        (first, second)
+    };
+
+    let mut doc = Document::with_types(7, types);
+    assert!(doc.open_journal(&path).is_ok());
+    assert_eq!(doc.current_node(), Some(first));
+    assert_eq!(doc.branches(), vec![first]);
+    assert!(doc.history_node(second).is_none());
+    assert_eq!(doc.get_property::<i32>(vec![START_NAME], COLOR), Some(1));
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn journal_torn_record() {
+    let types = Rc::new(types());
+    let path = journal_path("torn");
+    {
+        let mut doc = Document::with_types(7, types.clone());
+        assert!(doc.open_journal(&path).is_ok());
+        doc.create_entity().add(COLOR, 1);
+        assert!(doc.commit_transaction().is_ok());
+        doc.create_entity().add(COLOR, 2);
+        assert!(doc.commit_transaction().is_ok());
+    }
+
+    // simulate a crash in the middle of writing the last record
+    let size = std::fs::metadata(&path).unwrap().len();
+    let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    assert!(file.set_len(size - 3).is_ok());
+
+    let mut doc = Document::with_types(7, types.clone());
+    assert!(doc.open_journal(&path).is_ok());
+    assert_eq!(doc.history_size(), (1, 1));
+    assert_eq!(doc.entities(false).count(), 1);
+
+    // the journal is usable after the torn record is cut off
+    doc.create_entity().add(COLOR, 5);
+    assert!(doc.commit_transaction().is_ok());
+    drop(doc);
+
+    let mut doc = Document::with_types(7, types);
+    assert!(doc.open_journal(&path).is_ok());
+    assert_eq!(doc.history_size(), (2, 2));
+    assert_eq!(
+        doc.get_property::<i32>(vec![START_NAME + 1], COLOR),
+        Some(5)
+    );
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn journal_damaged_record() {
+    let types = Rc::new(types());
+    let path = journal_path("damaged");
+    {
+        let mut doc = Document::with_types(7, types.clone());
+        assert!(doc.open_journal(&path).is_ok());
+        doc.create_entity().add(COLOR, 1);
+        assert!(doc.commit_transaction().is_ok());
+        doc.create_entity().add(COLOR, 2);
+        assert!(doc.commit_transaction().is_ok());
+        doc.create_entity().add(COLOR, 3);
+        assert!(doc.commit_transaction().is_ok());
+    }
+
+    // a damaged record before the end isn't taken for a torn one, the file is left as is.
+    // The first record begins after the 8 bytes of the header with the checksum of its length,
+    // then the length and the payload.
+    let intact = std::fs::read(&path).unwrap();
+    for (pos, bit) in [(10, 1), (18, 1), (19, 0x80), (20, 0xff)] {
+        let mut data = intact.clone();
+        data[pos] ^= bit;
+        assert!(std::fs::write(&path, &data).is_ok());
+        let mut doc = Document::with_types(7, types.clone());
+        assert!(matches!(
+            doc.open_journal(&path),
+            Err(d3s::Error::Corrupted(_))
+        ));
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+    }
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn journal_after_save() {
+    let types = Rc::new(types());
+    let path = journal_path("save");
+    let mut file = vec![];
+    {
+        let mut doc = sample_document(types.clone());
+        assert!(doc.open_journal(&path).is_ok());
+        assert!(doc.save_to(&mut file).is_ok());
+        assert!(doc.clear_journal().is_ok());
+        doc.delete_entity(vec![START_NAME]);
+        assert!(doc.commit_transaction().is_ok());
+    }
+
+    let mut doc = Document::open_from(&mut file.as_slice(), types).unwrap();
+    assert!(doc.open_journal(&path).is_ok());
+    assert_eq!(doc.history_size(), (4, 4));
+    assert!(doc.get_entity(vec![START_NAME]).is_none());
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn key_registry() {
+    let mut types = types();
+    types
+        .name_key(COLOR, "color")
+        .describe_key(COLOR, "RGB color of the entity")
+        .define_key::<String>(TITLE + 10, "label");
+    assert_eq!(types.key_by_name("color"), Some(COLOR));
+    assert_eq!(types.key_name(TITLE + 10), Some("label"));
+    assert_eq!(types.key_by_name("title"), None);
+    types.name_key(WIDTH, "label");
+    assert_eq!(types.key_by_name("label"), Some(WIDTH));
+    assert_eq!(types.key_name(TITLE + 10), None);
+    types.name_key(TITLE + 10, "label");
+    assert_eq!(types.key_name(WIDTH), None);
+    let info = types.key_info(COLOR).unwrap();
+    assert_eq!(info.type_name, Some("i32"));
+    assert_eq!(info.description.as_deref(), Some("RGB color of the entity"));
+
+    let mut changes = EntityChanges {
+        ename: vec![START_NAME],
+        props: vec![],
+    };
+    assert!(changes.try_add(&types, COLOR, 1).is_ok());
+    assert!(changes
+        .try_add(&types, TITLE + 10, String::from("Door"))
+        .is_ok());
+    assert!(matches!(
+        changes.try_add(&types, COLOR, "red"),
+        Err(d3s::Error::TypeMismatch(COLOR))
+    ));
+    assert_eq!(changes.props.len(), 2);
+
+    // the file remembers the key names and rejects a registry with another meaning of the key
+    let types = Rc::new(types);
+    let mut file = vec![];
+    assert!(sample_document(types.clone()).save_to(&mut file).is_ok());
+    assert!(Document::open_from(&mut file.as_slice(), types).is_ok());
+
+    let mut renamed = self::types();
+    renamed.name_key(COLOR, "colour");
+    assert!(matches!(
+        Document::open_from(&mut file.as_slice(), Rc::new(renamed)),
+        Err(d3s::Error::KeyMismatch(COLOR))
+    ));
+}
+
+#[test]
+fn clipboard_roundtrip() {
+    use d3s::entity::PlainEntity;
+    use std::collections::BTreeSet;
+
+    let mut types = types();
+    types.name_key(COLOR, "color");
+    let types = Rc::new(types);
+    let mut doc = Document::with_types(222, types.clone());
+    doc.create_entity().add(COLOR, 11);
+    assert!(doc.commit_transaction().is_ok());
+    assert!(doc.switch(111).is_ok());
+    doc.create_entity().add(INS_DOC, 222 as DocId);
+    doc.create_entity()
+        .add(COLOR, 33)
+        .add(TITLE, String::from("Door"));
+    assert!(doc.commit_transaction().is_ok());
+
+    let selected = BTreeSet::from([vec![START_NAME, START_NAME], vec![START_NAME + 1]]);
+    let mut buf = vec![];
+    assert!(PlainEntity::save_all(&doc.copy(selected), &types, &mut buf).is_ok());
+
+    // another process reads the entities with its own registry
+    let mut other = self::types();
+    other.name_key(COLOR, "color");
+    let clipboard = PlainEntity::load_all(&other, &mut buf.as_slice()).unwrap();
+    assert_eq!(clipboard.len(), 2);
+    assert_eq!(clipboard[0].name, vec![START_NAME, START_NAME]);
+    assert_eq!(clipboard[1].name, vec![START_NAME + 1]);
+
+    // the inserted document is written with its children
+    let inserted = doc.copy(BTreeSet::from([vec![START_NAME]]));
+    let mut inserted_buf = vec![];
+    assert!(PlainEntity::save_all(&inserted, &types, &mut inserted_buf).is_ok());
+    let inserted = PlainEntity::load_all(&other, &mut inserted_buf.as_slice()).unwrap();
+    let children = inserted[0].children.as_ref().unwrap();
+    assert_eq!(children[0].name, vec![START_NAME, START_NAME]);
+    assert_eq!(children[0].props.len(), 1);
+
+    let mut pasted = Document::with_types(5, Rc::new(other));
+    assert!(pasted.paste(clipboard).is_ok());
+    assert!(pasted.commit_transaction().is_ok());
+    assert_eq!(
+        pasted.get_property::<i32>(vec![START_NAME], COLOR),
+        Some(11)
+    );
+    assert_eq!(
+        pasted.get_property::<i32>(vec![START_NAME + 1], COLOR),
+        Some(33)
+    );
+
+    let mut renamed = self::types();
+    renamed.name_key(COLOR, "colour");
+    assert!(matches!(
+        PlainEntity::load_all(&renamed, &mut buf.as_slice()),
+        Err(d3s::Error::KeyMismatch(COLOR))
+    ));
+
+    // a child must be named after its parent
+    let copied = |name: Vec<u32>, children: Option<Vec<PlainEntity>>| PlainEntity {
+        name,
+        props: vec![],
+        children,
+    };
+    let wrong = copied(vec![3, 4], Some(vec![copied(vec![1], None)]));
+    assert!(matches!(
+        pasted.paste(vec![wrong]),
+        Err(d3s::Error::Corrupted(_))
+    ));
+    let unnamed_child = copied(vec![3], Some(vec![copied(vec![], None)]));
+    for wrong in [copied(vec![], None), unnamed_child] {
+        let wrong = vec![wrong];
+        let mut wrong_buf = vec![];
+        assert!(PlainEntity::save_all(&wrong, &types, &mut wrong_buf).is_ok());
+        assert!(matches!(
+            PlainEntity::load_all(&types, &mut wrong_buf.as_slice()),
+            Err(d3s::Error::Corrupted(_))
+        ));
+        assert!(matches!(pasted.paste(wrong), Err(d3s::Error::Corrupted(_))));
+    }
+    assert!(matches!(
+        Document::open_from(&mut buf.as_slice(), types),
+        Err(d3s::Error::Corrupted(_))
+    ));
+}